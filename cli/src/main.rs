@@ -2,7 +2,10 @@ use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::Parser;
-use deskassistant_driver::{actions, EpdPage, UsbConnection};
+use deskassistant_driver::{
+    actions, DeviceConnection, DeviceEndpoint, DeviceSettings, DisplayColors, EpdImage,
+    EpdImageFormat, EpdPage, ExportOptions, EPD_HEIGHT, EPD_WIDTH,
+};
 
 #[derive(Debug, Clone, clap::Subcommand)]
 #[non_exhaustive]
@@ -22,6 +25,8 @@ enum CliCommand {
     UpdateUserImage {
         #[clap(value_parser, short, long)]
         image_file: PathBuf,
+        #[clap(flatten)]
+        export_options: ExportOptions,
     },
     /// Decode and send image for display on the EPD when the specified app (executable name) is active
     UpdateAppImage {
@@ -29,6 +34,8 @@ enum CliCommand {
         app_name: String,
         #[clap(value_parser, short, long)]
         image_file: PathBuf,
+        #[clap(flatten)]
+        export_options: ExportOptions,
     },
     /// Report an active app name
     #[clap(action)]
@@ -39,6 +46,89 @@ enum CliCommand {
     /// Retreive and list the saved app images
     #[clap(action)]
     ListAppImages,
+    /// Delete the saved image of the specified app
+    DeleteAppImage {
+        #[clap(value_parser, short, long)]
+        app_name: String,
+    },
+    /// Rename the saved image of the specified app
+    RenameAppImage {
+        #[clap(value_parser, short, long)]
+        app_name: String,
+        #[clap(value_parser, short, long)]
+        new_app_name: String,
+    },
+    /// Delete all saved app images
+    #[clap(action)]
+    DeleteAllAppImages,
+    /// Download the user image stored on the device
+    GetUserImage {
+        /// the file the image is saved to. The format is derived from the extension
+        #[clap(value_parser, short, long)]
+        out: PathBuf,
+    },
+    /// Download the image stored on the device for the specified app
+    GetAppImage {
+        #[clap(value_parser, short, long)]
+        app_name: String,
+        /// the file the image is saved to. The format is derived from the extension
+        #[clap(value_parser, short, long)]
+        out: PathBuf,
+    },
+    /// Read and write the device settings
+    Settings {
+        #[clap(subcommand)]
+        command: SettingsCommand,
+    },
+    /// Render the image exactly as it would be displayed on the EPD, without sending it
+    Preview {
+        #[clap(value_parser, short, long)]
+        image_file: PathBuf,
+        /// the file the preview is saved to. The format is derived from the extension
+        #[clap(value_parser, short, long)]
+        out: PathBuf,
+        /// the width of the display the preview is rendered for
+        #[clap(long, value_parser, default_value_t = EPD_WIDTH)]
+        width: u32,
+        /// the height of the display the preview is rendered for
+        #[clap(long, value_parser, default_value_t = EPD_HEIGHT)]
+        height: u32,
+        /// the colours of the display the preview is rendered for
+        #[clap(long, value_enum, default_value_t)]
+        colors: DisplayColors,
+        #[clap(flatten)]
+        export_options: ExportOptions,
+    },
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum SettingsCommand {
+    /// print all settings, or the specified one
+    Get {
+        #[clap(value_parser)]
+        key: Option<String>,
+    },
+    /// change settings, values are parsed as JSON or else taken as strings
+    Set {
+        #[clap(value_parser = parse_key_val, required = true)]
+        settings: Vec<(String, String)>,
+    },
+    /// replace all settings with the ones in the JSON file
+    Import {
+        #[clap(value_parser)]
+        file: PathBuf,
+    },
+    /// save all settings as JSON. Printed if no file is specified
+    Export {
+        #[clap(value_parser)]
+        file: Option<PathBuf>,
+    },
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, val)| (key.to_string(), val.to_string()))
+        .ok_or_else(|| format!("expected `key=value`, got `{s}`"))
 }
 
 /// the cli for the deskassistant project
@@ -47,6 +137,9 @@ enum CliCommand {
 struct Cli {
     #[clap(short, long, value_parser)]
     verbose: bool,
+    /// the device to connect to, `usb` or `unix:<path>` for a simulated device
+    #[clap(short, long, value_parser, default_value = "usb")]
+    device: DeviceEndpoint,
     #[clap(subcommand)]
     command: Option<CliCommand>,
 }
@@ -56,7 +149,31 @@ fn main() -> anyhow::Result<()> {
     log::debug!("init");
 
     let cli = Cli::parse();
-    let mut connection = UsbConnection::new()?;
+
+    // Previews are rendered without the device
+    if let Some(CliCommand::Preview {
+        image_file,
+        out,
+        width,
+        height,
+        colors,
+        export_options,
+    }) = cli.command
+    {
+        let format = EpdImageFormat {
+            width,
+            height,
+            colors,
+            bit_depth: export_options.bit_depth,
+        };
+        EpdImage::load_from_file(image_file)?
+            .preview(&format, &export_options)?
+            .save_to_file(out)?;
+
+        return Ok(());
+    }
+
+    let mut connection = DeviceConnection::new(&cli.device)?;
     // call handle events once to drive the hotplug callback
     connection.handle_events()?;
 
@@ -78,14 +195,29 @@ fn main() -> anyhow::Result<()> {
             CliCommand::SwitchPage { page } => {
                 actions::switch_page(&connection, page, timeout)?;
             }
-            CliCommand::UpdateUserImage { image_file } => {
-                actions::update_user_image_from_file(&connection, image_file, timeout)?;
+            CliCommand::UpdateUserImage {
+                image_file,
+                export_options,
+            } => {
+                actions::update_user_image_from_file(
+                    &connection,
+                    image_file,
+                    &export_options,
+                    timeout,
+                )?;
             }
             CliCommand::UpdateAppImage {
                 app_name,
                 image_file,
+                export_options,
             } => {
-                actions::update_app_image_from_file(&connection, app_name, image_file, timeout)?;
+                actions::update_app_image_from_file(
+                    &connection,
+                    app_name,
+                    image_file,
+                    &export_options,
+                    timeout,
+                )?;
             }
             CliCommand::ReportActiveApp { app_name } => {
                 actions::report_active_app(&connection, app_name, timeout)?;
@@ -94,6 +226,52 @@ fn main() -> anyhow::Result<()> {
                 let app_images_list = actions::retreive_app_images_list(&connection, timeout)?;
                 println!("{app_images_list:?}");
             }
+            CliCommand::DeleteAppImage { app_name } => {
+                actions::delete_app_image(&connection, app_name, timeout)?;
+            }
+            CliCommand::RenameAppImage {
+                app_name,
+                new_app_name,
+            } => {
+                actions::rename_app_image(&connection, app_name, new_app_name, timeout)?;
+            }
+            CliCommand::DeleteAllAppImages => {
+                actions::delete_all_app_images(&connection, timeout)?;
+            }
+            CliCommand::GetUserImage { out } => {
+                actions::retreive_user_image(&connection, timeout)?.save_to_file(out)?;
+            }
+            CliCommand::GetAppImage { app_name, out } => {
+                actions::retreive_app_image(&connection, app_name, timeout)?.save_to_file(out)?;
+            }
+            CliCommand::Settings { command } => match command {
+                SettingsCommand::Get { key } => {
+                    let settings = actions::retreive_settings(&connection, timeout)?;
+                    match key {
+                        Some(key) => println!("{}", settings.get(&key)?),
+                        None => println!("{}", settings.to_json()?),
+                    }
+                }
+                SettingsCommand::Set { settings: changes } => {
+                    let mut settings = actions::retreive_settings(&connection, timeout)?;
+                    for (key, value) in changes {
+                        settings.set(&key, &value)?;
+                    }
+                    actions::update_settings(&connection, &settings, timeout)?;
+                }
+                SettingsCommand::Import { file } => {
+                    let settings = DeviceSettings::from_json(&std::fs::read_to_string(file)?)?;
+                    actions::update_settings(&connection, &settings, timeout)?;
+                }
+                SettingsCommand::Export { file } => {
+                    let settings = actions::retreive_settings(&connection, timeout)?.to_json()?;
+                    match file {
+                        Some(file) => std::fs::write(file, settings)?,
+                        None => println!("{settings}"),
+                    }
+                }
+            },
+            CliCommand::Preview { .. } => unreachable!("previews are rendered without the device"),
         }
     }
 
@@ -0,0 +1,191 @@
+#![cfg(unix)]
+
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use deskassistant_driver::simulator::DeviceSimulator;
+use deskassistant_driver::socket;
+use deskassistant_driver::{DeviceSettings, EpdImage, EPD_HEIGHT, EPD_WIDTH};
+
+fn spawn_simulator(name: &str) -> PathBuf {
+    spawn_device(name, DeviceSimulator::new())
+}
+
+fn spawn_device(name: &str, device: DeviceSimulator) -> PathBuf {
+    let socket_path = std::env::temp_dir().join(format!(
+        "deskassistant-cli-test-{}-{name}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&socket_path);
+
+    let listener = UnixListener::bind(&socket_path).unwrap();
+    std::thread::spawn(move || socket::serve_simulator(device, listener));
+
+    socket_path
+}
+
+fn run_cli(socket_path: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_deskassistant_cli"))
+        .arg("--device")
+        .arg(format!("unix:{}", socket_path.display()))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn cli_against_simulator() {
+    let socket_path = spawn_simulator("commands");
+
+    let output = run_cli(&socket_path, &["switch-page", "user-image"]);
+    assert!(output.status.success());
+
+    let output = run_cli(&socket_path, &["status"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("UserImage"));
+
+    let output = run_cli(&socket_path, &["report-active-app", "--app-name", "code"]);
+    assert!(output.status.success());
+
+    let output = run_cli(&socket_path, &["list-app-images"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "[]");
+
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn cli_fails_without_device() {
+    let socket_path = std::env::temp_dir().join("deskassistant-cli-test-missing.sock");
+
+    let output = run_cli(&socket_path, &["status"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn preview_without_device() {
+    let out = std::env::temp_dir().join(format!(
+        "deskassistant-cli-test-{}-preview.png",
+        std::process::id()
+    ));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_deskassistant_cli"))
+        .args(["preview", "--dithering", "floyd-steinberg", "--image-file"])
+        .arg(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../test_images/Mandrill.png"))
+        .arg("--out")
+        .arg(&out)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let preview = EpdImage::load_from_file(&out).unwrap();
+    assert_eq!(preview.dimensions(), (EPD_WIDTH, EPD_HEIGHT));
+
+    let _ = std::fs::remove_file(&out);
+}
+
+#[test]
+fn get_user_image_roundtrip() {
+    let socket_path = spawn_simulator("get-user-image");
+    let out = std::env::temp_dir().join(format!(
+        "deskassistant-cli-test-{}-user-image.png",
+        std::process::id()
+    ));
+
+    let output = run_cli(&socket_path, &["get-user-image", "--out"]);
+    assert!(!output.status.success());
+
+    let image_file = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../test_images/Mandrill.png");
+    let output = run_cli(
+        &socket_path,
+        &[
+            "update-user-image",
+            "--image-file",
+            image_file.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success());
+
+    let output = run_cli(
+        &socket_path,
+        &["get-user-image", "--out", out.to_str().unwrap()],
+    );
+    assert!(output.status.success());
+
+    let user_image = EpdImage::load_from_file(&out).unwrap();
+    assert_eq!(user_image.dimensions(), (EPD_WIDTH, EPD_HEIGHT));
+
+    let _ = std::fs::remove_file(&out);
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn settings() {
+    let socket_path = spawn_device(
+        "settings",
+        DeviceSimulator::new().with_settings(String::from(
+            r#"{ "startup_page": "overview", "led_brightness": 20 }"#,
+        )),
+    );
+
+    let output = run_cli(
+        &socket_path,
+        &[
+            "settings",
+            "set",
+            "startup_page=app-screen",
+            "led_brightness=5",
+        ],
+    );
+    assert!(output.status.success());
+
+    let output = run_cli(&socket_path, &["settings", "get", "startup_page"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "\"app-screen\""
+    );
+
+    let output = run_cli(&socket_path, &["settings", "set", "does_not_exist=0"]);
+    assert!(!output.status.success());
+
+    // Only the settings stored on the device are written back
+    let output = run_cli(&socket_path, &["settings", "export"]);
+    assert!(output.status.success());
+    assert_eq!(
+        DeviceSettings::from_json(&String::from_utf8_lossy(&output.stdout)).unwrap(),
+        DeviceSettings::from_json(r#"{ "startup_page": "app-screen", "led_brightness": 5 }"#)
+            .unwrap()
+    );
+
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn read_and_fix_stored_settings() {
+    let socket_path = spawn_device(
+        "invalid-settings",
+        DeviceSimulator::new().with_settings(String::from(r#"{ "full_refresh_interval": 1000 }"#)),
+    );
+
+    // Settings are read as stored on the device, whatever their values
+    let output = run_cli(&socket_path, &["settings", "get", "full_refresh_interval"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "1000");
+
+    let output = run_cli(&socket_path, &["settings", "export"]);
+    assert!(output.status.success());
+
+    let output = run_cli(
+        &socket_path,
+        &["settings", "set", "full_refresh_interval=20"],
+    );
+    assert!(output.status.success());
+
+    let output = run_cli(&socket_path, &["settings", "get", "full_refresh_interval"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "20");
+
+    let _ = std::fs::remove_file(&socket_path);
+}
@@ -2,83 +2,183 @@ use std::ffi::CString;
 use std::path::PathBuf;
 use std::time::Duration;
 
+use crate::region::{self, REGION_ALIGNMENT};
 use crate::{
-    DeviceMessage, DeviceStatus, EpdImage, EpdImageFormat, EpdPage, HostMessage, UsbConnection,
-    EPD_HEIGHT, EPD_WIDTH,
+    compression, BitDepth, DeviceMessage, DeviceSettings, DeviceStatus, DriverError, Encoding,
+    EpdImage, EpdImageFormat, EpdPage, ExportOptions, Features, HostMessage, PackedImage, Region,
+    Transport,
 };
 
 pub fn retreive_device_status(
-    connection: &UsbConnection,
+    connection: &impl Transport,
     timeout: Duration,
-) -> anyhow::Result<DeviceStatus> {
+) -> Result<DeviceStatus, DriverError> {
     connection.send_host_message(HostMessage::RequestDeviceStatus, timeout)?;
 
     match connection.read_device_message(timeout)? {
         DeviceMessage::DeviceStatus(status) => Ok(status),
-        msg => Err(anyhow::anyhow!(
-            "failed to retreive device status, received unexpected device message: `{:?}`",
-            msg
-        )),
+        msg => Err(DriverError::UnexpectedMessage {
+            expected: "DeviceStatus",
+            actual: msg.variant_name(),
+        }),
     }
 }
 
-pub fn refresh_display(connection: &UsbConnection, timeout: Duration) -> anyhow::Result<()> {
+pub fn refresh_display(connection: &impl Transport, timeout: Duration) -> Result<(), DriverError> {
     connection.send_host_message(HostMessage::RefreshDisplay, timeout)?;
     Ok(())
 }
 
 pub fn switch_page(
-    connection: &UsbConnection,
+    connection: &impl Transport,
     page: EpdPage,
     timeout: Duration,
-) -> anyhow::Result<()> {
+) -> Result<(), DriverError> {
     connection.send_host_message(HostMessage::SwitchPage(page), timeout)?;
     Ok(())
 }
 
 pub fn update_user_image_from_file(
-    connection: &UsbConnection,
+    connection: &impl Transport,
     img_file: PathBuf,
+    options: &ExportOptions,
     timeout: Duration,
-) -> anyhow::Result<()> {
+) -> Result<(), DriverError> {
     if !img_file.exists() {
-        return Err(anyhow::anyhow!(
-            "`{}` does not exist. Exiting",
-            img_file.display()
-        ));
+        return Err(DriverError::FileNotFound(img_file));
     }
 
-    let format = EpdImageFormat {
-        width: EPD_WIDTH,
-        height: EPD_HEIGHT,
+    update_user_image(
+        connection,
+        EpdImage::load_from_file(&img_file)?,
+        options,
+        timeout,
+    )
+}
+
+pub fn update_user_image(
+    connection: &impl Transport,
+    image: EpdImage,
+    options: &ExportOptions,
+    timeout: Duration,
+) -> Result<(), DriverError> {
+    let format = export_format(connection, options, "UpdateUserImage")?;
+    let image_bytes = image.export(&format, options)?;
+
+    send_user_image(connection, format, &image_bytes, timeout)
+}
+
+/// Sends only the region of the user image that changed since it was last sent.
+///
+/// `last_sent` holds the user image as it was last sent and is replaced by the new one after it was sent.
+/// The whole image is sent if there is no last sent image in the same format or the firmware can't update regions.
+/// Returns the sent region, `None` if nothing changed.
+pub fn update_user_image_region(
+    connection: &impl Transport,
+    image: EpdImage,
+    last_sent: &mut Option<PackedImage>,
+    options: &ExportOptions,
+    timeout: Duration,
+) -> Result<Option<Region>, DriverError> {
+    let format = export_format(connection, options, "UpdateUserImage")?;
+    let image_bytes = image.export(&format, options)?;
+
+    let dirty = match last_sent {
+        Some(previous) if previous.format == format => {
+            region::dirty_region(&format, &previous.data, &image_bytes)?
+        }
+        _ => Some(Region::full(&format)),
+    };
+    let Some(dirty) = dirty else {
+        return Ok(None);
+    };
+
+    let region = if connection
+        .capabilities()
+        .features
+        .contains(Features::PARTIAL_UPDATE)
+    {
+        dirty.align(REGION_ALIGNMENT, format.width)
+    } else {
+        Region::full(&format)
+    };
+
+    let region_bytes = if region == Region::full(&format) {
+        None
+    } else {
+        Some(region::crop_packed(&format, &image_bytes, region)?)
     };
-    let image_bytes = EpdImage::load_from_file(&img_file)?.export(&format)?;
 
-    connection.send_host_message(HostMessage::UpdateUserImage { format }, timeout)?;
+    // Forgotten before sending, so that a failed transfer leaves no image the device may not have stored
+    *last_sent = None;
+
+    if let Some(region_bytes) = region_bytes {
+        let (encoding, region_bytes) = encode_image_data(connection, &region_bytes);
+
+        connection.send_host_message(
+            HostMessage::UpdateRegion {
+                x: region.x as u16,
+                y: region.y as u16,
+                format: region.format(&format),
+                encoding,
+            },
+            timeout,
+        )?;
+        connection.transmit_host_data(&region_bytes, timeout)?;
+    } else {
+        send_user_image(connection, format, &image_bytes, timeout)?;
+    }
+
+    *last_sent = Some(PackedImage {
+        format,
+        data: image_bytes,
+    });
+    Ok(Some(region))
+}
+
+fn send_user_image(
+    connection: &impl Transport,
+    format: EpdImageFormat,
+    image_bytes: &[u8],
+    timeout: Duration,
+) -> Result<(), DriverError> {
+    let (encoding, image_bytes) = encode_image_data(connection, image_bytes);
+
+    connection.send_host_message(HostMessage::UpdateUserImage { format, encoding }, timeout)?;
     connection.transmit_host_data(&image_bytes, timeout)?;
 
-    connection.send_host_message(HostMessage::DataComplete, timeout)?;
     Ok(())
 }
 
 pub fn update_app_image_from_file(
-    connection: &UsbConnection,
+    connection: &impl Transport,
     app_name: String,
     img_file: PathBuf,
+    options: &ExportOptions,
     timeout: Duration,
-) -> anyhow::Result<()> {
+) -> Result<(), DriverError> {
     if !img_file.exists() {
-        return Err(anyhow::anyhow!(
-            "`{}` does not exist. Exiting",
-            img_file.display()
-        ));
+        return Err(DriverError::FileNotFound(img_file));
     }
 
-    let format = EpdImageFormat {
-        width: EPD_WIDTH,
-        height: EPD_HEIGHT,
-    };
-    let image_bytes = EpdImage::load_from_file(&img_file)?.export(&format)?;
+    update_app_image(
+        connection,
+        app_name,
+        EpdImage::load_from_file(&img_file)?,
+        options,
+        timeout,
+    )
+}
+
+pub fn update_app_image(
+    connection: &impl Transport,
+    app_name: String,
+    image: EpdImage,
+    options: &ExportOptions,
+    timeout: Duration,
+) -> Result<(), DriverError> {
+    let format = export_format(connection, options, "UpdateAppImage")?;
+    let (encoding, image_bytes) = encode_image_data(connection, &image.export(&format, options)?);
 
     let app_name_cstr = CString::new(app_name)?.into_bytes_with_nul();
     let str_len = (app_name_cstr.len() as u16).saturating_sub(1);
@@ -87,58 +187,251 @@ pub fn update_app_image_from_file(
         HostMessage::UpdateAppImage {
             app_name_str_len: str_len,
             format,
+            encoding,
         },
         timeout,
     )?;
 
     // First send the app name string
     connection.transmit_host_data(&app_name_cstr, timeout)?;
-    connection.send_host_message(HostMessage::DataComplete, timeout)?;
 
     // Then the app image data
     connection.transmit_host_data(&image_bytes, timeout)?;
-    connection.send_host_message(HostMessage::DataComplete, timeout)?;
 
     Ok(())
 }
 
+/// Encodes the image data in the encoding supported by the device that makes it the smallest
+fn encode_image_data(connection: &impl Transport, image_bytes: &[u8]) -> (Encoding, Vec<u8>) {
+    compression::encode_smallest(image_bytes, connection.capabilities().features)
+}
+
+/// The format for the display of the device, with the bit depth of the export options
+fn export_format(
+    connection: &impl Transport,
+    options: &ExportOptions,
+    command: &'static str,
+) -> Result<EpdImageFormat, DriverError> {
+    let capabilities = connection.capabilities();
+    if options.bit_depth == BitDepth::Bpp2 {
+        capabilities.require(Features::GRAYSCALE, command)?;
+    }
+
+    Ok(EpdImageFormat {
+        bit_depth: options.bit_depth,
+        ..capabilities.epd_format()
+    })
+}
+
 pub fn report_active_app(
-    connection: &UsbConnection,
+    connection: &impl Transport,
     app_name: String,
     timeout: Duration,
-) -> anyhow::Result<()> {
+) -> Result<(), DriverError> {
     let app_name_cstr = CString::new(app_name)?.into_bytes_with_nul();
     let str_len = (app_name_cstr.len() as u16).saturating_sub(1);
 
     connection.send_host_message(HostMessage::ReportActiveApp { str_len }, timeout)?;
     connection.transmit_host_data(&app_name_cstr, timeout)?;
-    connection.send_host_message(HostMessage::DataComplete, timeout)?;
     Ok(())
 }
 
 pub fn retreive_app_images_list(
-    connection: &UsbConnection,
+    connection: &impl Transport,
     timeout: Duration,
-) -> anyhow::Result<Vec<String>> {
+) -> Result<Vec<String>, DriverError> {
     connection.send_host_message(HostMessage::RequestListAppImages, timeout)?;
 
     let str_len = match connection.read_device_message(timeout)? {
         DeviceMessage::ListAppImages { str_len } => str_len,
         msg => {
-            return Err(anyhow::anyhow!(
-                "failed to retreive app images list. Received unexpected device message: `{:?}`",
-                msg
-            ))
+            return Err(DriverError::UnexpectedMessage {
+                expected: "ListAppImages",
+                actual: msg.variant_name(),
+            })
         }
     };
 
-    let mut app_images_list_str = connection.receive_device_data(timeout, None)?;
+    let app_images_list_str =
+        receive_device_str(connection.receive_device_data(timeout, None)?, str_len)?;
 
-    app_images_list_str.resize(str_len as usize + 1, 0x00);
+    Ok(app_images_list_str.lines().map(|s| s.to_string()).collect())
+}
 
-    let app_images_list_str = CString::from_vec_with_nul(app_images_list_str)
-        .map_err(anyhow::Error::from)?
-        .into_string()?;
+/// Deleting an app image that does not exist does nothing
+pub fn delete_app_image(
+    connection: &impl Transport,
+    app_name: String,
+    timeout: Duration,
+) -> Result<(), DriverError> {
+    connection
+        .capabilities()
+        .require(Features::MANAGE_APP_IMAGES, "DeleteAppImage")?;
 
-    Ok(app_images_list_str.lines().map(|s| s.to_string()).collect())
+    let app_name_cstr = CString::new(app_name)?.into_bytes_with_nul();
+    let str_len = (app_name_cstr.len() as u16).saturating_sub(1);
+
+    connection.send_host_message(
+        HostMessage::DeleteAppImage {
+            app_name_str_len: str_len,
+        },
+        timeout,
+    )?;
+    connection.transmit_host_data(&app_name_cstr, timeout)?;
+    Ok(())
+}
+
+/// An existing app image with the new name is replaced
+pub fn rename_app_image(
+    connection: &impl Transport,
+    app_name: String,
+    new_app_name: String,
+    timeout: Duration,
+) -> Result<(), DriverError> {
+    connection
+        .capabilities()
+        .require(Features::MANAGE_APP_IMAGES, "RenameAppImage")?;
+
+    let app_name_cstr = CString::new(app_name)?.into_bytes_with_nul();
+    let str_len = (app_name_cstr.len() as u16).saturating_sub(1);
+    let new_app_name_cstr = CString::new(new_app_name)?.into_bytes_with_nul();
+    let new_str_len = (new_app_name_cstr.len() as u16).saturating_sub(1);
+
+    connection.send_host_message(
+        HostMessage::RenameAppImage {
+            app_name_str_len: str_len,
+            new_app_name_str_len: new_str_len,
+        },
+        timeout,
+    )?;
+
+    // First send the current app name
+    connection.transmit_host_data(&app_name_cstr, timeout)?;
+
+    // Then the new one
+    connection.transmit_host_data(&new_app_name_cstr, timeout)?;
+    Ok(())
+}
+
+pub fn delete_all_app_images(
+    connection: &impl Transport,
+    timeout: Duration,
+) -> Result<(), DriverError> {
+    connection
+        .capabilities()
+        .require(Features::MANAGE_APP_IMAGES, "DeleteAllAppImages")?;
+    connection.send_host_message(HostMessage::DeleteAllAppImages, timeout)?;
+    Ok(())
+}
+
+pub fn retreive_settings(
+    connection: &impl Transport,
+    timeout: Duration,
+) -> Result<DeviceSettings, DriverError> {
+    connection
+        .capabilities()
+        .require(Features::SETTINGS, "RequestSettings")?;
+    connection.send_host_message(HostMessage::RequestSettings, timeout)?;
+
+    let str_len = match connection.read_device_message(timeout)? {
+        DeviceMessage::Settings { str_len } => str_len,
+        msg => {
+            return Err(DriverError::UnexpectedMessage {
+                expected: "Settings",
+                actual: msg.variant_name(),
+            })
+        }
+    };
+
+    let settings_str = receive_device_str(connection.receive_device_data(timeout, None)?, str_len)?;
+
+    DeviceSettings::from_json(&settings_str)
+}
+
+/// Validates the settings and replaces the ones stored on the device
+pub fn update_settings(
+    connection: &impl Transport,
+    settings: &DeviceSettings,
+    timeout: Duration,
+) -> Result<(), DriverError> {
+    connection
+        .capabilities()
+        .require(Features::SETTINGS, "UpdateSettings")?;
+    settings.validate()?;
+
+    let settings_cstr = CString::new(settings.to_json()?)?.into_bytes_with_nul();
+    let str_len = (settings_cstr.len() as u16).saturating_sub(1);
+
+    connection.send_host_message(HostMessage::UpdateSettings { str_len }, timeout)?;
+    connection.transmit_host_data(&settings_cstr, timeout)?;
+    Ok(())
+}
+
+pub fn retreive_user_image(
+    connection: &impl Transport,
+    timeout: Duration,
+) -> Result<EpdImage, DriverError> {
+    connection
+        .capabilities()
+        .require(Features::READ_IMAGES, "RequestUserImage")?;
+    connection.send_host_message(HostMessage::RequestUserImage, timeout)?;
+
+    receive_stored_image(connection, timeout)
+}
+
+pub fn retreive_app_image(
+    connection: &impl Transport,
+    app_name: String,
+    timeout: Duration,
+) -> Result<EpdImage, DriverError> {
+    connection
+        .capabilities()
+        .require(Features::READ_IMAGES, "RequestAppImage")?;
+
+    let app_name_cstr = CString::new(app_name)?.into_bytes_with_nul();
+    let str_len = (app_name_cstr.len() as u16).saturating_sub(1);
+
+    connection.send_host_message(
+        HostMessage::RequestAppImage {
+            app_name_str_len: str_len,
+        },
+        timeout,
+    )?;
+    connection.transmit_host_data(&app_name_cstr, timeout)?;
+
+    receive_stored_image(connection, timeout)
+}
+
+/// Takes the string of `str_len` bytes out of the received data, dropping the nul terminator and padding that follow it
+fn receive_device_str(mut data: Vec<u8>, str_len: u16) -> Result<String, DriverError> {
+    if data.len() < str_len as usize {
+        return Err(DriverError::TransferTooShort {
+            expected: str_len as usize,
+            len: data.len(),
+        });
+    }
+    data.truncate(str_len as usize);
+
+    Ok(String::from_utf8(data)?)
+}
+
+/// Receives the answer to an image request and decodes the packed image data
+fn receive_stored_image(
+    connection: &impl Transport,
+    timeout: Duration,
+) -> Result<EpdImage, DriverError> {
+    let format = match connection.read_device_message(timeout)? {
+        DeviceMessage::StoredImage { format } => format,
+        DeviceMessage::ImageNotFound => return Err(DriverError::ImageNotFound),
+        msg => {
+            return Err(DriverError::UnexpectedMessage {
+                expected: "StoredImage",
+                actual: msg.variant_name(),
+            })
+        }
+    };
+
+    let data = connection.receive_device_data(timeout, None)?;
+
+    EpdImage::from_packed(&data, &format)
 }
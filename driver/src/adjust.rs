@@ -0,0 +1,267 @@
+use image::imageops::{self, FilterType};
+use image::{GrayImage, RgbImage};
+use pyo3::prelude::*;
+
+use crate::{DriverError, ExportOptions};
+
+/// The share of the darkest and brightest pixels that are ignored when stretching the levels
+const AUTO_LEVELS_CLIP: f32 = 0.005;
+
+/// The filter used when resizing the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[pyclass]
+pub enum ResizeFilter {
+    /// Nearest neighbour, keeps hard edges of pixel art and screenshots
+    Nearest,
+    /// Linear
+    Triangle,
+    /// Cubic
+    CatmullRom,
+    #[default]
+    Gaussian,
+    /// Lanczos with window 3, the sharpest
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Applies the tonal adjustments of the export options to the grayscale image, in the order
+/// auto-levels, brightness, contrast, gamma, invert.
+///
+/// Returns the threshold level for reducing the adjusted image to black and white.
+pub fn adjust(image: &mut GrayImage, options: &ExportOptions) -> Result<u8, DriverError> {
+    validate(options)?;
+
+    let (low, high) = if options.auto_levels {
+        levels(image)
+    } else {
+        (0x00, 0xff)
+    };
+    let lut = lut(options, low, high);
+
+    for px in image.pixels_mut() {
+        px.0[0] = lut[px.0[0] as usize];
+    }
+
+    if options.otsu_threshold {
+        Ok(imageproc::contrast::otsu_level(image))
+    } else {
+        Ok(options.threshold)
+    }
+}
+
+/// Like [adjust], but applies the adjustments to every channel of a colour image.
+/// The levels are determined from its luma, so that the hues are preserved.
+pub fn adjust_rgb(image: &mut RgbImage, options: &ExportOptions) -> Result<(), DriverError> {
+    validate(options)?;
+
+    let (low, high) = if options.auto_levels {
+        levels(&imageops::grayscale(image))
+    } else {
+        (0x00, 0xff)
+    };
+    let lut = lut(options, low, high);
+
+    for px in image.pixels_mut() {
+        px.0 = px.0.map(|c| lut[c as usize]);
+    }
+
+    Ok(())
+}
+
+fn validate(options: &ExportOptions) -> Result<(), DriverError> {
+    if options.gamma.is_nan() || options.gamma <= 0.0 {
+        return Err(DriverError::InvalidExportOption(
+            "gamma must be greater than 0",
+        ));
+    }
+    if options.contrast.is_nan() || options.contrast < 0.0 {
+        return Err(DriverError::InvalidExportOption(
+            "contrast must not be negative",
+        ));
+    }
+
+    Ok(())
+}
+
+/// The lookup table mapping every level to its adjusted level
+fn lut(options: &ExportOptions, low: u8, high: u8) -> [u8; 256] {
+    std::array::from_fn(|v| {
+        // stretch the levels to the full range
+        let mut v = if high > low {
+            (v as f32 - low as f32) * 255.0 / (high - low) as f32
+        } else {
+            v as f32
+        };
+
+        v += options.brightness as f32;
+        v = (v - 128.0) * options.contrast + 128.0;
+        v = 255.0 * (v.clamp(0.0, 255.0) / 255.0).powf(1.0 / options.gamma);
+
+        if options.invert {
+            v = 255.0 - v;
+        }
+
+        v.round().clamp(0.0, 255.0) as u8
+    })
+}
+
+/// The darkest and brightest levels of the image, ignoring outliers
+fn levels(image: &GrayImage) -> (u8, u8) {
+    let mut histogram = [0_usize; 256];
+    for px in image.pixels() {
+        histogram[px.0[0] as usize] += 1;
+    }
+
+    let clip = (image.len() as f32 * AUTO_LEVELS_CLIP) as usize;
+
+    let mut cnt = 0;
+    let low = histogram
+        .iter()
+        .position(|n| {
+            cnt += n;
+            cnt > clip
+        })
+        .unwrap_or(0x00);
+
+    let mut cnt = 0;
+    let high = 0xff
+        - histogram
+            .iter()
+            .rev()
+            .position(|n| {
+                cnt += n;
+                cnt > clip
+            })
+            .unwrap_or(0x00);
+
+    (low as u8, high as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma, Rgb, RgbImage};
+
+    use super::{adjust, adjust_rgb};
+    use crate::ExportOptions;
+
+    fn gradient() -> GrayImage {
+        GrayImage::from_fn(256, 1, |x, _| Luma([x as u8]))
+    }
+
+    #[test]
+    fn defaults_change_nothing() {
+        let mut image = gradient();
+        let threshold = adjust(&mut image, &ExportOptions::default()).unwrap();
+
+        assert_eq!(image, gradient());
+        assert_eq!(threshold, ExportOptions::default().threshold);
+    }
+
+    #[test]
+    fn brightness_contrast_gamma_invert() {
+        let options = ExportOptions {
+            brightness: 10,
+            ..Default::default()
+        };
+        let mut image = gradient();
+        adjust(&mut image, &options).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0[0], 10);
+        assert_eq!(image.get_pixel(250, 0).0[0], 255);
+
+        let options = ExportOptions {
+            contrast: 2.0,
+            ..Default::default()
+        };
+        let mut image = gradient();
+        adjust(&mut image, &options).unwrap();
+        assert_eq!(image.get_pixel(64, 0).0[0], 0);
+        assert_eq!(image.get_pixel(128, 0).0[0], 128);
+        assert_eq!(image.get_pixel(192, 0).0[0], 255);
+
+        let options = ExportOptions {
+            gamma: 2.0,
+            ..Default::default()
+        };
+        let mut image = gradient();
+        adjust(&mut image, &options).unwrap();
+        assert!(image.get_pixel(64, 0).0[0] > 64);
+        assert_eq!(image.get_pixel(255, 0).0[0], 255);
+
+        let options = ExportOptions {
+            invert: true,
+            ..Default::default()
+        };
+        let mut image = gradient();
+        adjust(&mut image, &options).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0[0], 255);
+        assert_eq!(image.get_pixel(255, 0).0[0], 0);
+    }
+
+    #[test]
+    fn auto_levels_stretches() {
+        let mut image = GrayImage::from_fn(100, 1, |x, _| Luma([100 + x as u8]));
+        let options = ExportOptions {
+            auto_levels: true,
+            ..Default::default()
+        };
+        adjust(&mut image, &options).unwrap();
+
+        assert_eq!(image.get_pixel(0, 0).0[0], 0);
+        assert_eq!(image.get_pixel(99, 0).0[0], 255);
+    }
+
+    #[test]
+    fn otsu_threshold_separates_modes() {
+        let mut image =
+            GrayImage::from_fn(100, 1, |x, _| if x < 50 { Luma([20]) } else { Luma([60]) });
+        let options = ExportOptions {
+            otsu_threshold: true,
+            ..Default::default()
+        };
+        let threshold = adjust(&mut image, &options).unwrap();
+
+        assert!((20..60).contains(&threshold));
+    }
+
+    #[test]
+    fn auto_levels_rgb_keeps_hue() {
+        // a dim red and a dim gray
+        let mut image = RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([0x60, 0x20, 0x20])
+            } else {
+                Rgb([0x40, 0x40, 0x40])
+            }
+        });
+        let options = ExportOptions {
+            auto_levels: true,
+            ..Default::default()
+        };
+        adjust_rgb(&mut image, &options).unwrap();
+
+        let red = image.get_pixel(0, 0).0;
+        assert!(red[0] > red[1] && red[1] == red[2]);
+        let gray = image.get_pixel(1, 0).0;
+        assert!(gray[0] == gray[1] && gray[1] == gray[2]);
+    }
+
+    #[test]
+    fn invalid_gamma() {
+        let options = ExportOptions {
+            gamma: 0.0,
+            ..Default::default()
+        };
+        assert!(adjust(&mut gradient(), &options).is_err());
+    }
+}
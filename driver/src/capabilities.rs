@@ -0,0 +1,323 @@
+use std::cell::Cell;
+use std::ops::BitOr;
+use std::time::Duration;
+
+use pyo3::prelude::*;
+
+use crate::{
+    BitDepth, DeviceMessage, DriverError, EpdImageFormat, HostMessage, Transport, EPD_HEIGHT,
+    EPD_WIDTH, PROTOCOL_VERSION,
+};
+
+/// How long to wait for the answer to the handshake.
+/// Firmware that predates the handshake does not answer at all
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The optional features of the protocol a device supports, as a bitmap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(pub u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Reading back the stored user image and app images
+    pub const READ_IMAGES: Self = Self(1 << 0);
+    /// Deleting and renaming app images
+    pub const MANAGE_APP_IMAGES: Self = Self(1 << 1);
+    /// Reading and writing the settings
+    pub const SETTINGS: Self = Self(1 << 2);
+    /// The long form of the device status
+    pub const EXTENDED_STATUS: Self = Self(1 << 3);
+    /// Images with four gray levels at two bits per pixel
+    pub const GRAYSCALE: Self = Self(1 << 4);
+    /// Updating a region of the user image
+    pub const PARTIAL_UPDATE: Self = Self(1 << 5);
+    /// Image data compressed with [crate::Encoding::Rle]
+    pub const COMPRESSION_RLE: Self = Self(1 << 6);
+    /// Image data compressed with [crate::Encoding::Lz]
+    pub const COMPRESSION_LZ: Self = Self(1 << 7);
+    /// Verifying the CRC32 of transfers and acknowledging them
+    pub const CHECKSUM: Self = Self(1 << 8);
+    /// Transfers of the host that declare their length up front with a DataLength message
+    pub const TRANSFER_LENGTH: Self = Self(1 << 9);
+
+    /// All features known to the host
+    pub const ALL: Self = Self(
+        Self::READ_IMAGES.0
+            | Self::MANAGE_APP_IMAGES.0
+            | Self::SETTINGS.0
+            | Self::EXTENDED_STATUS.0
+            | Self::GRAYSCALE.0
+            | Self::PARTIAL_UPDATE.0
+            | Self::COMPRESSION_RLE.0
+            | Self::COMPRESSION_LZ.0
+            | Self::CHECKSUM.0
+            | Self::TRANSFER_LENGTH.0,
+    );
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The colours the display can show
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, num_derive::FromPrimitive,
+)]
+#[pyclass]
+pub enum DisplayColors {
+    #[default]
+    BlackWhite = 0,
+    /// Black, white and red
+    BlackWhiteRed = 1,
+    /// Black, white and yellow
+    BlackWhiteYellow = 2,
+}
+
+impl DisplayColors {
+    /// The third colour in rgb8, if the display has one
+    pub fn accent(self) -> Option<[u8; 3]> {
+        match self {
+            DisplayColors::BlackWhite => None,
+            DisplayColors::BlackWhiteRed => Some([0xff, 0x00, 0x00]),
+            DisplayColors::BlackWhiteYellow => Some([0xff, 0xff, 0x00]),
+        }
+    }
+}
+
+impl From<DisplayColors> for u8 {
+    fn from(colors: DisplayColors) -> Self {
+        colors as u8
+    }
+}
+
+impl TryFrom<u8> for DisplayColors {
+    type Error = DriverError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        num_traits::FromPrimitive::from_u8(value).ok_or(DriverError::InvalidDisplayColors(value))
+    }
+}
+
+/// What was negotiated with the device in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[pyclass]
+pub struct Capabilities {
+    /// The protocol version implemented by the firmware, 0 if it predates the handshake
+    #[pyo3(get)]
+    pub protocol_version: u8,
+    /// major, minor, patch
+    #[pyo3(get)]
+    pub firmware_version: Option<(u8, u8, u8)>,
+    pub features: Features,
+    /// The native width of the display in px
+    #[pyo3(get)]
+    pub display_width: u16,
+    /// The native height of the display in px
+    #[pyo3(get)]
+    pub display_height: u16,
+    #[pyo3(get)]
+    pub display_colors: DisplayColors,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::legacy()
+    }
+}
+
+impl Capabilities {
+    /// The capabilities of firmware that predates the handshake, which only understands the original messages
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: 0,
+            firmware_version: None,
+            features: Features::NONE,
+            display_width: EPD_WIDTH as u16,
+            display_height: EPD_HEIGHT as u16,
+            display_colors: DisplayColors::BlackWhite,
+        }
+    }
+
+    /// The format images are exported in for the display of the device, with one bit per pixel
+    pub fn epd_format(&self) -> EpdImageFormat {
+        EpdImageFormat {
+            width: self.display_width as u32,
+            height: self.display_height as u32,
+            colors: self.display_colors,
+            bit_depth: BitDepth::Bpp1,
+        }
+    }
+
+    /// Fails with [DriverError::Unsupported] if the feature needed for the command is not supported
+    pub fn require(&self, feature: Features, command: &'static str) -> Result<(), DriverError> {
+        if self.features.contains(feature) {
+            Ok(())
+        } else {
+            Err(DriverError::Unsupported {
+                command,
+                firmware: self.firmware_description(),
+            })
+        }
+    }
+
+    fn firmware_description(&self) -> String {
+        match self.firmware_version {
+            Some((major, minor, patch)) => format!("v{major}.{minor}.{patch}"),
+            None => format!("with protocol version {}", self.protocol_version),
+        }
+    }
+}
+
+#[pymethods]
+impl Capabilities {
+    pub fn supports_reading_images(&self) -> bool {
+        self.features.contains(Features::READ_IMAGES)
+    }
+
+    pub fn supports_managing_app_images(&self) -> bool {
+        self.features.contains(Features::MANAGE_APP_IMAGES)
+    }
+
+    pub fn supports_settings(&self) -> bool {
+        self.features.contains(Features::SETTINGS)
+    }
+
+    pub fn supports_grayscale(&self) -> bool {
+        self.features.contains(Features::GRAYSCALE)
+    }
+
+    pub fn supports_partial_update(&self) -> bool {
+        self.features.contains(Features::PARTIAL_UPDATE)
+    }
+
+    pub fn supports_compression(&self) -> bool {
+        self.features.contains(Features::COMPRESSION_RLE)
+            || self.features.contains(Features::COMPRESSION_LZ)
+    }
+}
+
+/// The capabilities of a connection, negotiated with the handshake when they are first needed.
+///
+/// Handshaking when the device arrives would block the event handling for the full [HANDSHAKE_TIMEOUT]
+/// with firmware that predates the handshake. Instead the first message sent over the connection waits for it.
+#[derive(Debug, Default)]
+pub(crate) struct LazyCapabilities(Cell<Option<Capabilities>>);
+
+impl LazyCapabilities {
+    /// Returns the capabilities, performing the handshake if it did not happen yet
+    pub(crate) fn negotiate(&self, connection: &impl Transport) -> Capabilities {
+        if let Some(capabilities) = self.0.get() {
+            return capabilities;
+        }
+
+        // Set up front, so that the messages of the handshake itself don't start another one
+        self.0.set(Some(Capabilities::legacy()));
+        let capabilities = handshake(connection, HANDSHAKE_TIMEOUT).unwrap_or_else(|e| {
+            log::warn!("handshake failed with Err {e}, assuming legacy firmware");
+            Capabilities::legacy()
+        });
+        self.0.set(Some(capabilities));
+
+        capabilities
+    }
+
+    /// Forgets the capabilities, for when the device is connected or disconnected
+    pub(crate) fn reset(&self) {
+        self.0.set(None);
+    }
+}
+
+/// Exchanges protocol versions and features with the device.
+///
+/// The handshake is the first message with a variant firmware that predates it does not know.
+/// Such firmware discards frames with unknown variants without answering, as [crate::simulator::DeviceSimulator::legacy] does,
+/// so the host falls back to [Capabilities::legacy] if the device does not answer.
+pub fn handshake(
+    connection: &impl Transport,
+    timeout: Duration,
+) -> Result<Capabilities, DriverError> {
+    connection.send_host_message(
+        HostMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+        },
+        timeout,
+    )?;
+
+    match connection.read_device_message(timeout) {
+        Ok(DeviceMessage::Capabilities {
+            protocol_version,
+            firmware_version,
+            features,
+            display_width,
+            display_height,
+            display_colors,
+        }) => {
+            let legacy = Capabilities::legacy();
+            // Firmware that does not know the size of its display leaves it zeroed
+            let (display_width, display_height) = if display_width == 0 || display_height == 0 {
+                (legacy.display_width, legacy.display_height)
+            } else {
+                (display_width, display_height)
+            };
+
+            Ok(Capabilities {
+                protocol_version,
+                firmware_version: Some(firmware_version),
+                features,
+                display_width,
+                display_height,
+                display_colors,
+            })
+        }
+        Ok(msg) => Err(DriverError::UnexpectedMessage {
+            expected: "Capabilities",
+            actual: msg.variant_name(),
+        }),
+        Err(DriverError::UsbTimeout) => {
+            log::info!("device did not answer the handshake, assuming legacy firmware");
+            Ok(Capabilities::legacy())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, Features};
+    use crate::DriverError;
+
+    #[test]
+    fn require() {
+        let capabilities = Capabilities {
+            protocol_version: 1,
+            firmware_version: Some((1, 2, 3)),
+            features: Features::READ_IMAGES | Features::SETTINGS,
+            ..Capabilities::legacy()
+        };
+
+        assert!(capabilities
+            .require(Features::SETTINGS, "RequestSettings")
+            .is_ok());
+
+        let err = capabilities
+            .require(Features::MANAGE_APP_IMAGES, "DeleteAppImage")
+            .unwrap_err();
+        assert!(matches!(err, DriverError::Unsupported { .. }));
+        assert_eq!(
+            err.to_string(),
+            "`DeleteAppImage` is unsupported by firmware v1.2.3"
+        );
+
+        assert!(Capabilities::legacy()
+            .require(Features::READ_IMAGES, "RequestUserImage")
+            .is_err());
+    }
+}
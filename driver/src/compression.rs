@@ -0,0 +1,306 @@
+use crate::{DriverError, Features};
+
+/// The size of the window back-references of [Encoding::Lz] can reach, small enough for the RAM of the firmware
+const LZ_WINDOW: usize = 256;
+/// Shorter matches are sent as literals, as a back-reference takes two bytes
+const LZ_MATCH_MIN: usize = 3;
+const LZ_MATCH_MAX: usize = LZ_MATCH_MIN + u8::MAX as usize;
+/// The maximum length of a run and a sequence of literals in [Encoding::Rle]
+const RLE_RUN_MAX: usize = 128;
+
+/// The encoding of the image data in a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, num_derive::FromPrimitive)]
+pub enum Encoding {
+    #[default]
+    Raw = 0,
+    /// Run-length encoding as in PackBits.
+    ///
+    /// A header byte `n` is followed by `n + 1` literal bytes for `0..=127`,
+    /// or by a single byte that is repeated `1 - n` times for `-127..=-1`, taken as `i8`.
+    Rle = 1,
+    /// LZSS with a window of 256 bytes.
+    ///
+    /// A flag byte is followed by eight items, MSB first a cleared flag bit marks a literal byte,
+    /// a set bit a back-reference of two bytes: the distance minus one and the length minus three.
+    Lz = 2,
+}
+
+impl Encoding {
+    /// The feature the device needs to support to decode it
+    pub fn feature(self) -> Features {
+        match self {
+            Encoding::Raw => Features::NONE,
+            Encoding::Rle => Features::COMPRESSION_RLE,
+            Encoding::Lz => Features::COMPRESSION_LZ,
+        }
+    }
+}
+
+impl From<Encoding> for u8 {
+    fn from(encoding: Encoding) -> Self {
+        encoding as u8
+    }
+}
+
+impl TryFrom<u8> for Encoding {
+    type Error = DriverError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        num_traits::FromPrimitive::from_u8(value).ok_or(DriverError::InvalidEncoding(value))
+    }
+}
+
+pub fn encode(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+    match encoding {
+        Encoding::Raw => data.to_vec(),
+        Encoding::Rle => rle_encode(data),
+        Encoding::Lz => lz_encode(data),
+    }
+}
+
+/// Encodes the data with the supported encoding that makes it the smallest.
+///
+/// Falls back to [Encoding::Raw] if no encoding is supported or none makes it smaller.
+pub fn encode_smallest(data: &[u8], features: Features) -> (Encoding, Vec<u8>) {
+    [Encoding::Rle, Encoding::Lz]
+        .into_iter()
+        .filter(|encoding| features.contains(encoding.feature()))
+        .map(|encoding| (encoding, encode(encoding, data)))
+        .filter(|(_, encoded)| encoded.len() < data.len())
+        .min_by_key(|(_, encoded)| encoded.len())
+        .unwrap_or_else(|| (Encoding::Raw, data.to_vec()))
+}
+
+/// Decodes until `len` bytes are produced, ignoring what follows them
+pub fn decode(encoding: Encoding, data: &[u8], len: usize) -> Result<Vec<u8>, DriverError> {
+    match encoding {
+        Encoding::Raw => data
+            .get(..len)
+            .map(|data| data.to_vec())
+            .ok_or(DriverError::InvalidEncodedData("raw data is too short")),
+        Encoding::Rle => rle_decode(data, len),
+        Encoding::Lz => lz_decode(data, len),
+    }
+}
+
+fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let run = data[pos..]
+            .iter()
+            .take(RLE_RUN_MAX)
+            .take_while(|&&byte| byte == data[pos])
+            .count();
+
+        if run >= 2 {
+            encoded.push((1 - run as i16) as u8);
+            encoded.push(data[pos]);
+            pos += run;
+        } else {
+            // Literals until the next run of at least three bytes, shorter runs are cheaper as literals
+            let start = pos;
+            while pos < data.len() && pos - start < RLE_RUN_MAX {
+                if data.get(pos + 2).is_some()
+                    && data[pos] == data[pos + 1]
+                    && data[pos] == data[pos + 2]
+                {
+                    break;
+                }
+                pos += 1;
+            }
+            encoded.push((pos - start - 1) as u8);
+            encoded.extend_from_slice(&data[start..pos]);
+        }
+    }
+
+    encoded
+}
+
+fn rle_decode(data: &[u8], len: usize) -> Result<Vec<u8>, DriverError> {
+    let mut decoded = Vec::with_capacity(len);
+    let mut input = data.iter().copied();
+    let mut next = || {
+        input
+            .next()
+            .ok_or(DriverError::InvalidEncodedData("rle data is too short"))
+    };
+
+    while decoded.len() < len {
+        match next()? as i8 {
+            -128 => {}
+            header @ 0.. => {
+                for _ in 0..=header {
+                    decoded.push(next()?);
+                }
+            }
+            header => {
+                let byte = next()?;
+                decoded.extend(std::iter::repeat_n(byte, (1 - header as i16) as usize));
+            }
+        }
+    }
+
+    decoded.truncate(len);
+    Ok(decoded)
+}
+
+fn lz_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let flags_pos = encoded.len();
+        encoded.push(0x00);
+
+        for bit in 0..8 {
+            if pos >= data.len() {
+                break;
+            }
+
+            let (distance, len) = lz_longest_match(data, pos);
+            if len >= LZ_MATCH_MIN {
+                encoded[flags_pos] |= 0x80 >> bit;
+                encoded.push((distance - 1) as u8);
+                encoded.push((len - LZ_MATCH_MIN) as u8);
+                pos += len;
+            } else {
+                encoded.push(data[pos]);
+                pos += 1;
+            }
+        }
+    }
+
+    encoded
+}
+
+/// The distance and length of the longest match for the data at `pos` in the window before it
+fn lz_longest_match(data: &[u8], pos: usize) -> (usize, usize) {
+    let max_len = LZ_MATCH_MAX.min(data.len() - pos);
+    let mut longest = (0, 0);
+
+    for distance in 1..=LZ_WINDOW.min(pos) {
+        let start = pos - distance;
+        // Matches may overlap the data they are copied to
+        let len = (0..max_len)
+            .take_while(|&i| data[start + i] == data[pos + i])
+            .count();
+
+        if len > longest.1 {
+            longest = (distance, len);
+            if len == max_len {
+                break;
+            }
+        }
+    }
+
+    longest
+}
+
+fn lz_decode(data: &[u8], len: usize) -> Result<Vec<u8>, DriverError> {
+    let mut decoded = Vec::with_capacity(len);
+    let mut input = data.iter().copied();
+    let mut next = || {
+        input
+            .next()
+            .ok_or(DriverError::InvalidEncodedData("lz data is too short"))
+    };
+
+    while decoded.len() < len {
+        let flags = next()?;
+
+        for bit in 0..8 {
+            if decoded.len() >= len {
+                break;
+            }
+
+            if flags & (0x80 >> bit) == 0 {
+                decoded.push(next()?);
+                continue;
+            }
+
+            let distance = next()? as usize + 1;
+            let match_len = next()? as usize + LZ_MATCH_MIN;
+            if distance > decoded.len() {
+                return Err(DriverError::InvalidEncodedData(
+                    "lz back-reference before the start of the data",
+                ));
+            }
+            for _ in 0..match_len {
+                decoded.push(decoded[decoded.len() - distance]);
+            }
+        }
+    }
+
+    decoded.truncate(len);
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, encode_smallest, Encoding};
+    use crate::Features;
+    use proptest::prelude::*;
+
+    fn image_data() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..2000),
+            // Mostly white with a few runs of other bytes, like packed images
+            prop::collection::vec(
+                (prop_oneof![Just(0xff_u8), any::<u8>()], 1..300_usize),
+                0..20
+            )
+            .prop_map(|runs| runs
+                .into_iter()
+                .flat_map(|(byte, len)| std::iter::repeat_n(byte, len))
+                .collect()),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(data in image_data()) {
+            for encoding in [Encoding::Raw, Encoding::Rle, Encoding::Lz] {
+                let mut encoded = encode(encoding, &data);
+                // Trailing bytes like the padding of the last data message are ignored
+                encoded.extend_from_slice(&[0x00; 62]);
+                prop_assert_eq!(&decode(encoding, &encoded, data.len()).unwrap(), &data);
+            }
+        }
+    }
+
+    #[test]
+    fn rle_packbits() {
+        let data = [0xaa, 0xaa, 0xaa, 0x80, 0x00, 0x2a, 0xaa, 0xaa, 0xaa, 0xaa];
+        assert_eq!(
+            encode(Encoding::Rle, &data),
+            vec![0xfe, 0xaa, 0x02, 0x80, 0x00, 0x2a, 0xfd, 0xaa]
+        );
+    }
+
+    #[test]
+    fn encode_smallest_falls_back_to_raw() {
+        let white = vec![0xff; 15000];
+        let (encoding, encoded) = encode_smallest(&white, Features::ALL);
+        assert_ne!(encoding, Encoding::Raw);
+        assert!(encoded.len() < 300);
+
+        assert_eq!(
+            encode_smallest(&white, Features::NONE),
+            (Encoding::Raw, white.clone())
+        );
+        assert_eq!(
+            encode_smallest(&[0x12, 0x34], Features::ALL),
+            (Encoding::Raw, vec![0x12, 0x34])
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let encoded = encode(Encoding::Lz, &[0xff; 100]);
+        assert!(decode(Encoding::Lz, &encoded[..encoded.len() - 1], 100).is_err());
+        assert!(decode(Encoding::Rle, &[0x05, 0x01], 6).is_err());
+        assert!(decode(Encoding::Raw, &[0x01], 2).is_err());
+    }
+}
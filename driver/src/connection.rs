@@ -2,7 +2,8 @@ use rusb::UsbContext;
 use std::sync::mpsc;
 use std::time::Duration;
 
-use crate::{DeviceMessage, HostMessage};
+use crate::capabilities::LazyCapabilities;
+use crate::{Capabilities, DeviceMessage, DriverError, HostMessage, Transport};
 
 pub const USB_DEVICE_VID: u16 = 0x0483;
 pub const USB_DEVICE_PID: u16 = 0x0456;
@@ -62,10 +63,11 @@ pub struct UsbConnection {
     hotplug_reg: rusb::Registration<rusb::Context>,
     hotplugmessage_receiver: mpsc::Receiver<HotplugMessage>,
     device_handle: Option<rusb::DeviceHandle<rusb::Context>>,
+    capabilities: LazyCapabilities,
 }
 
 impl UsbConnection {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new() -> Result<Self, DriverError> {
         let context = rusb::Context::new()?;
         let (sender, receiver) = mpsc::channel();
 
@@ -81,6 +83,7 @@ impl UsbConnection {
             hotplug_reg,
             hotplugmessage_receiver: receiver,
             device_handle: None,
+            capabilities: LazyCapabilities::default(),
         })
     }
 
@@ -89,8 +92,14 @@ impl UsbConnection {
     }
 
     /// Handles already-pending non-synchronous events. It drives the hotplug support,
-    /// which then automatically connects to the device when it is found
-    pub fn handle_events(&mut self) -> anyhow::Result<()> {
+    /// which then automatically connects to the device when it is found.
+    ///
+    /// The handshake is not done here but before the first message to the device, so this never blocks on it.
+    ///
+    /// Returns whether the device was connected or disconnected.
+    pub fn handle_events(&mut self) -> Result<bool, DriverError> {
+        let mut changed = false;
+
         // If timeout is less than a microsecond, handle_events() only processes already-pending events
         // and then returns in non-blocking style
         self.context.handle_events(Some(Duration::from_nanos(1)))?;
@@ -98,77 +107,55 @@ impl UsbConnection {
         for hotplugmessage in self.hotplugmessage_receiver.try_iter() {
             match hotplugmessage {
                 HotplugMessage::DeviceArrived(arrived_device) => {
-                    if let Some(mut device_handle) = arrived_device.open().ok() {
+                    if let Ok(mut device_handle) = arrived_device.open() {
                         // By dropping the old handle the claimed interfaces get disconnected.
-                        self.device_handle.take().map(|h| drop(h));
+                        drop(self.device_handle.take());
 
                         device_handle.claim_interface(ITF_NUM_MSG)?;
 
                         self.device_handle.replace(device_handle);
+                        self.capabilities.reset();
+                        changed = true;
                     }
                 }
                 HotplugMessage::DeviceLeft(left_device) => {
                     if let Some(ref device_handle) = self.device_handle {
                         if device_handle.device() == left_device {
-                            self.device_handle.take().map(|h| drop(h));
+                            drop(self.device_handle.take());
+                            self.capabilities.reset();
+                            changed = true;
                         }
                     }
                 }
             }
         }
 
-        Ok(())
+        Ok(changed)
     }
+}
 
-    /// Sends a host message.
-    /// Blocks until finished
-    pub fn send_host_message(&self, msg: HostMessage, timeout: Duration) -> anyhow::Result<()> {
+impl Transport for UsbConnection {
+    fn send_host_message(&self, msg: HostMessage, timeout: Duration) -> Result<(), DriverError> {
         let data = msg.into_data();
 
         let device_handle = self
             .device_handle
             .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Device not connected."))?;
+            .ok_or(DriverError::NotConnected)?;
+        self.capabilities.negotiate(self);
 
         device_handle.write_bulk(EPNUM_HOST_MSG, &data, timeout)?;
         Ok(())
     }
 
-    /// Transmits the entire slice to the device with data messages.
-    /// Blocks until finished
-    pub fn transmit_host_data(&self, data: &[u8], timeout: Duration) -> anyhow::Result<()> {
-        let mut chunk_iter = data.chunks_exact(USB_HOST_MSG_LEN - 1);
-        for chunk in chunk_iter.by_ref() {
-            self.send_host_message(
-                HostMessage::Data {
-                    data: chunk[0..USB_HOST_MSG_LEN - 1].try_into().unwrap(),
-                },
-                timeout,
-            )?;
-        }
-
-        let mut remainder = chunk_iter.remainder().to_vec();
-        remainder.resize(USB_HOST_MSG_LEN - 1, 0x00);
-
-        self.send_host_message(
-            HostMessage::Data {
-                data: remainder.try_into().unwrap(),
-            },
-            timeout,
-        )?;
-
-        Ok(())
-    }
-
-    /// Reads a message from the device.
-    /// Blocks until finished
-    pub fn read_device_message(&self, timeout: Duration) -> anyhow::Result<DeviceMessage> {
+    fn read_device_message(&self, timeout: Duration) -> Result<DeviceMessage, DriverError> {
         let mut data = [0_u8; USB_HOST_MSG_LEN];
 
         let device_handle = self
             .device_handle
             .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Device not connected."))?;
+            .ok_or(DriverError::NotConnected)?;
+        self.capabilities.negotiate(self);
 
         device_handle.read_bulk(EPNUM_DEVICE_MSG, &mut data, timeout)?;
 
@@ -179,38 +166,12 @@ impl UsbConnection {
         Ok(device_message)
     }
 
-    /// Reads data from the device until a DataComplete Message or the optionally specified number of messages are received.
-    /// Blocks until finished
-    pub fn receive_device_data(&self, timeout: Duration, msg_cnt: Option<usize>) -> anyhow::Result<Vec<u8>> {
-        let mut accumulated_data = vec![];
-
-        let device_handle = self
-            .device_handle
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Device not connected."))?;
-
-        let msg_cnt = msg_cnt.unwrap_or(usize::MAX);
-
-        for _ in 0..msg_cnt {
-            let mut data = [0_u8; USB_HOST_MSG_LEN];
-            device_handle.read_bulk(EPNUM_DEVICE_MSG, &mut data, timeout)?;
-
-            let device_message = DeviceMessage::from_data(&data)?;
-            log::debug!("received device data: `{device_message:02x?}`");
-
-            match device_message {
-                DeviceMessage::Data { data } => accumulated_data.append(&mut data.to_vec()),
-                DeviceMessage::DataComplete => break,
-                msg => {
-                    return Err(anyhow::anyhow!(
-                        "received unexpected device message `{:?}`",
-                        msg
-                    ))
-                }
-            }
+    fn capabilities(&self) -> Capabilities {
+        if !self.is_connected() {
+            return Capabilities::legacy();
         }
 
-        Ok(accumulated_data)
+        self.capabilities.negotiate(self)
     }
 }
 
@@ -0,0 +1,377 @@
+use image::{GrayImage, Luma, RgbImage};
+use pyo3::prelude::*;
+
+/// The divisor and the `(dx, dy, weight)` of the Floyd-Steinberg error diffusion
+const FLOYD_STEINBERG: (i32, &[(i64, i64, i32)]) =
+    (16, &[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)]);
+
+/// The divisor and the `(dx, dy, weight)` of the Atkinson error diffusion
+const ATKINSON: (i32, &[(i64, i64, i32)]) = (
+    8,
+    &[
+        (1, 0, 1),
+        (2, 0, 1),
+        (-1, 1, 1),
+        (0, 1, 1),
+        (1, 1, 1),
+        (0, 2, 1),
+    ],
+);
+
+/// The weights of the rgb channels when comparing colours, their share of the luma in per mille
+const LUMA_WEIGHTS: [i64; 3] = [299, 587, 114];
+
+/// The 4x4 bayer threshold map
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// The 8x8 bayer threshold map
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// How a grayscale image is reduced to black and white pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[pyclass]
+pub enum Dithering {
+    /// A hard threshold, without dithering
+    #[default]
+    Threshold,
+    /// Floyd-Steinberg error diffusion
+    FloydSteinberg,
+    /// Atkinson error diffusion, which only diffuses 3/4 of the error and keeps more contrast
+    Atkinson,
+    /// Ordered dithering with a 4x4 bayer matrix
+    Bayer4x4,
+    /// Ordered dithering with a 8x8 bayer matrix
+    Bayer8x8,
+}
+
+impl Dithering {
+    /// Reduces the image to pixels that are either 0x00 or 0xff.
+    pub fn apply(self, image: &GrayImage, threshold: u8) -> GrayImage {
+        match self {
+            Dithering::Threshold => imageproc::contrast::threshold(image, threshold),
+            Dithering::FloydSteinberg => {
+                error_diffusion(image, |v| threshold_level(v, threshold), FLOYD_STEINBERG)
+            }
+            Dithering::Atkinson => {
+                error_diffusion(image, |v| threshold_level(v, threshold), ATKINSON)
+            }
+            Dithering::Bayer4x4 => ordered(image, threshold, &BAYER_4X4),
+            Dithering::Bayer8x8 => ordered(image, threshold, &BAYER_8X8),
+        }
+    }
+
+    /// Reduces the image to `levels` evenly spaced gray levels, e.g. 0x00, 0x55, 0xaa and 0xff for 4 levels.
+    ///
+    /// Without dithering every pixel becomes the closest level.
+    pub fn apply_levels(self, image: &GrayImage, levels: u8) -> GrayImage {
+        let step = 0xff / (levels.max(2) as i32 - 1);
+
+        match self {
+            Dithering::Threshold => GrayImage::from_fn(image.width(), image.height(), |x, y| {
+                Luma([closest_level(image.get_pixel(x, y).0[0] as i32, step) as u8])
+            }),
+            Dithering::FloydSteinberg => {
+                error_diffusion(image, |v| closest_level(v, step), FLOYD_STEINBERG)
+            }
+            Dithering::Atkinson => error_diffusion(image, |v| closest_level(v, step), ATKINSON),
+            Dithering::Bayer4x4 => levels_ordered(image, step, &BAYER_4X4),
+            Dithering::Bayer8x8 => levels_ordered(image, step, &BAYER_8X8),
+        }
+    }
+
+    /// Reduces the colour image to the colours of the palette.
+    /// Returns the palette index of every pixel, line by line.
+    ///
+    /// Without dithering every pixel becomes the closest colour of the palette.
+    pub fn apply_palette(self, image: &RgbImage, palette: &[[u8; 3]]) -> Vec<usize> {
+        match self {
+            Dithering::Threshold => image
+                .pixels()
+                .map(|px| nearest(px.0.map(|c| c as i32), palette))
+                .collect(),
+            Dithering::FloydSteinberg => palette_error_diffusion(image, palette, FLOYD_STEINBERG),
+            Dithering::Atkinson => palette_error_diffusion(image, palette, ATKINSON),
+            Dithering::Bayer4x4 => palette_ordered(image, palette, &BAYER_4X4),
+            Dithering::Bayer8x8 => palette_ordered(image, palette, &BAYER_8X8),
+        }
+    }
+}
+
+fn threshold_level(value: i32, threshold: u8) -> i32 {
+    if value > threshold as i32 {
+        0xff
+    } else {
+        0x00
+    }
+}
+
+/// The multiple of `step` that is closest to the value, clamped to the range of a gray level
+fn closest_level(value: i32, step: i32) -> i32 {
+    ((value as f32 / step as f32).round() as i32 * step).clamp(0x00, 0xff)
+}
+
+/// Diffuses the quantization error of each pixel to its neighbours.
+/// `quantize` maps a value to its level, `weights` are `(dx, dy, weight)`, the weights are divided by `divisor`
+fn error_diffusion(
+    image: &GrayImage,
+    quantize: impl Fn(i32) -> i32,
+    (divisor, weights): (i32, &[(i64, i64, i32)]),
+) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let mut values = image
+        .pixels()
+        .map(|px| px.0[0] as i32)
+        .collect::<Vec<i32>>();
+
+    for y in 0..height as i64 {
+        for x in 0..width as i64 {
+            let i = (y * width as i64 + x) as usize;
+            let old = values[i];
+            let new = quantize(old);
+            let error = old - new;
+            values[i] = new;
+
+            for &(dx, dy, weight) in weights {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || nx >= width as i64 || ny >= height as i64 {
+                    continue;
+                }
+                values[(ny * width as i64 + nx) as usize] += error * weight / divisor;
+            }
+        }
+    }
+
+    GrayImage::from_fn(width, height, |x, y| {
+        Luma([values[(y * width + x) as usize] as u8])
+    })
+}
+
+/// Compares each pixel against the threshold, offset by the tiled threshold map
+fn ordered<const N: usize>(image: &GrayImage, threshold: u8, map: &[[u8; N]; N]) -> GrayImage {
+    let n = N as u32;
+    let levels = (n * n) as f32;
+
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let offset = (map[(y % n) as usize][(x % n) as usize] as f32 + 0.5) / levels - 0.5;
+        // Clamped so that solid black and white stay solid
+        let px_threshold = (threshold as f32 + offset * 255.0).clamp(0.0, 254.0);
+
+        if image.get_pixel(x, y).0[0] as f32 > px_threshold {
+            Luma([0xff])
+        } else {
+            Luma([0x00])
+        }
+    })
+}
+
+/// Like [ordered], offsetting each pixel by up to half a step before picking the closest level
+fn levels_ordered<const N: usize>(image: &GrayImage, step: i32, map: &[[u8; N]; N]) -> GrayImage {
+    let n = N as u32;
+    let levels = (n * n) as f32;
+
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let offset = (map[(y % n) as usize][(x % n) as usize] as f32 + 0.5) / levels - 0.5;
+        let value = image.get_pixel(x, y).0[0] as f32 + offset * step as f32;
+
+        Luma([closest_level(value.round() as i32, step) as u8])
+    })
+}
+
+/// The index of the palette colour that is closest to the pixel
+fn nearest(px: [i32; 3], palette: &[[u8; 3]]) -> usize {
+    (0..palette.len())
+        .min_by_key(|&i| {
+            (0..3)
+                .map(|c| LUMA_WEIGHTS[c] * (px[c] as i64 - palette[i][c] as i64).pow(2))
+                .sum::<i64>()
+        })
+        .unwrap_or(0)
+}
+
+/// Like [error_diffusion], but diffuses the error of every channel
+fn palette_error_diffusion(
+    image: &RgbImage,
+    palette: &[[u8; 3]],
+    (divisor, weights): (i32, &[(i64, i64, i32)]),
+) -> Vec<usize> {
+    let (width, height) = image.dimensions();
+    let mut values = image
+        .pixels()
+        .map(|px| px.0.map(|c| c as i32))
+        .collect::<Vec<[i32; 3]>>();
+    let mut indices = vec![0; values.len()];
+
+    for y in 0..height as i64 {
+        for x in 0..width as i64 {
+            let i = (y * width as i64 + x) as usize;
+            let old = values[i];
+            indices[i] = nearest(old, palette);
+            let new = palette[indices[i]];
+
+            for &(dx, dy, weight) in weights {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || nx >= width as i64 || ny >= height as i64 {
+                    continue;
+                }
+                let neighbour = &mut values[(ny * width as i64 + nx) as usize];
+                for c in 0..3 {
+                    neighbour[c] += (old[c] - new[c] as i32) * weight / divisor;
+                }
+            }
+        }
+    }
+
+    indices
+}
+
+/// Like [ordered], offsetting every channel before picking the closest colour of the palette
+fn palette_ordered<const N: usize>(
+    image: &RgbImage,
+    palette: &[[u8; 3]],
+    map: &[[u8; N]; N],
+) -> Vec<usize> {
+    let n = N as u32;
+    let levels = (n * n) as f32;
+
+    image
+        .enumerate_pixels()
+        .map(|(x, y, px)| {
+            let offset = (map[(y % n) as usize][(x % n) as usize] as f32 + 0.5) / levels - 0.5;
+            // Solid colours of the palette are not offset, so that they stay solid
+            if palette.contains(&px.0) {
+                return nearest(px.0.map(|c| c as i32), palette);
+            }
+
+            nearest(
+                px.0.map(|c| (c as f32 + offset * 255.0).round() as i32),
+                palette,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma, Rgb, RgbImage};
+
+    use super::Dithering;
+
+    const ALL: [Dithering; 5] = [
+        Dithering::Threshold,
+        Dithering::FloydSteinberg,
+        Dithering::Atkinson,
+        Dithering::Bayer4x4,
+        Dithering::Bayer8x8,
+    ];
+
+    fn white_ratio(image: &GrayImage) -> f32 {
+        image.pixels().filter(|px| px.0[0] == 0xff).count() as f32 / image.len() as f32
+    }
+
+    #[test]
+    fn output_is_black_and_white() {
+        let gradient = GrayImage::from_fn(64, 16, |x, _| Luma([(x * 4) as u8]));
+
+        for dithering in ALL {
+            let dithered = dithering.apply(&gradient, 0x88);
+            assert_eq!(dithered.dimensions(), gradient.dimensions());
+            assert!(
+                dithered
+                    .pixels()
+                    .all(|px| px.0[0] == 0x00 || px.0[0] == 0xff),
+                "{dithering:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn solid_colors_stay_solid() {
+        let black = GrayImage::from_pixel(16, 16, Luma([0x00]));
+        let white = GrayImage::from_pixel(16, 16, Luma([0xff]));
+
+        for dithering in ALL {
+            assert_eq!(white_ratio(&dithering.apply(&black, 0x88)), 0.0);
+            assert_eq!(white_ratio(&dithering.apply(&white, 0x88)), 1.0);
+        }
+    }
+
+    #[test]
+    fn mid_gray_is_dithered() {
+        let gray = GrayImage::from_pixel(32, 32, Luma([0x80]));
+
+        assert_eq!(white_ratio(&Dithering::Threshold.apply(&gray, 0x88)), 0.0);
+
+        for dithering in [
+            Dithering::FloydSteinberg,
+            Dithering::Bayer4x4,
+            Dithering::Bayer8x8,
+        ] {
+            let ratio = white_ratio(&dithering.apply(&gray, 0x80));
+            assert!((0.4..=0.6).contains(&ratio), "{dithering:?}: {ratio}");
+        }
+    }
+
+    #[test]
+    fn four_levels() {
+        let gradient = GrayImage::from_fn(64, 16, |x, _| Luma([(x * 4) as u8]));
+
+        for dithering in ALL {
+            let dithered = dithering.apply_levels(&gradient, 4);
+            assert!(
+                dithered
+                    .pixels()
+                    .all(|px| [0x00, 0x55, 0xaa, 0xff].contains(&px.0[0])),
+                "{dithering:?}"
+            );
+
+            for level in [0x00, 0x55, 0xaa, 0xff] {
+                let solid = GrayImage::from_pixel(16, 16, Luma([level]));
+                assert_eq!(
+                    dithering.apply_levels(&solid, 4),
+                    solid,
+                    "{dithering:?}: {level}"
+                );
+            }
+        }
+
+        // Between two levels only those two are used
+        let gray = GrayImage::from_pixel(32, 32, Luma([0x80]));
+        let dithered = Dithering::FloydSteinberg.apply_levels(&gray, 4);
+        assert!(dithered
+            .pixels()
+            .all(|px| px.0[0] == 0x55 || px.0[0] == 0xaa));
+    }
+
+    #[test]
+    fn palette() {
+        let palette = [[0x00, 0x00, 0x00], [0xff, 0xff, 0xff], [0xff, 0x00, 0x00]];
+
+        for dithering in ALL {
+            for (i, color) in palette.iter().enumerate() {
+                let solid = RgbImage::from_pixel(16, 16, Rgb(*color));
+                assert!(
+                    dithering
+                        .apply_palette(&solid, &palette)
+                        .iter()
+                        .all(|&index| index == i),
+                    "{dithering:?}: {color:?}"
+                );
+            }
+        }
+
+        // A dark red is dithered with red and black
+        let dark_red = RgbImage::from_pixel(32, 32, Rgb([0x80, 0x00, 0x00]));
+        let indices = Dithering::FloydSteinberg.apply_palette(&dark_red, &palette);
+        assert!(indices.contains(&0) && indices.contains(&2));
+        assert!(!indices.contains(&1));
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(unix)]
+use crate::socket::SocketConnection;
+use crate::{Capabilities, DeviceMessage, DriverError, HostMessage, Transport, UsbConnection};
+
+/// Where the device can be reached.
+///
+/// Parsed from `usb` for the physical device, or `unix:<path>` for a stand-in device listening on a unix socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEndpoint {
+    Usb,
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for DeviceEndpoint {
+    type Err = DriverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "usb" => Ok(Self::Usb),
+            #[cfg(unix)]
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            _ => Err(DriverError::InvalidDeviceEndpoint(s.to_string())),
+        }
+    }
+}
+
+/// A connection to the device through one of the supported endpoints
+pub enum DeviceConnection {
+    Usb(UsbConnection),
+    #[cfg(unix)]
+    Socket(SocketConnection),
+}
+
+impl DeviceConnection {
+    pub fn new(endpoint: &DeviceEndpoint) -> Result<Self, DriverError> {
+        match endpoint {
+            DeviceEndpoint::Usb => Ok(Self::Usb(UsbConnection::new()?)),
+            #[cfg(unix)]
+            DeviceEndpoint::Unix(path) => Ok(Self::Socket(SocketConnection::new(path))),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self {
+            Self::Usb(connection) => connection.is_connected(),
+            #[cfg(unix)]
+            Self::Socket(connection) => connection.is_connected(),
+        }
+    }
+
+    /// Handles pending events, connecting to the device when it is found.
+    ///
+    /// Returns whether the device was connected or disconnected.
+    pub fn handle_events(&mut self) -> Result<bool, DriverError> {
+        match self {
+            Self::Usb(connection) => connection.handle_events(),
+            #[cfg(unix)]
+            Self::Socket(connection) => connection.handle_events(),
+        }
+    }
+}
+
+impl Transport for DeviceConnection {
+    fn send_host_message(&self, msg: HostMessage, timeout: Duration) -> Result<(), DriverError> {
+        match self {
+            Self::Usb(connection) => connection.send_host_message(msg, timeout),
+            #[cfg(unix)]
+            Self::Socket(connection) => connection.send_host_message(msg, timeout),
+        }
+    }
+
+    fn read_device_message(&self, timeout: Duration) -> Result<DeviceMessage, DriverError> {
+        match self {
+            Self::Usb(connection) => connection.read_device_message(timeout),
+            #[cfg(unix)]
+            Self::Socket(connection) => connection.read_device_message(timeout),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        match self {
+            Self::Usb(connection) => connection.capabilities(),
+            #[cfg(unix)]
+            Self::Socket(connection) => connection.capabilities(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::DeviceEndpoint;
+
+    #[test]
+    fn parse_device_endpoint() {
+        assert_eq!(
+            "usb".parse::<DeviceEndpoint>().unwrap(),
+            DeviceEndpoint::Usb
+        );
+        assert_eq!(
+            "unix:/tmp/deskassistant.sock"
+                .parse::<DeviceEndpoint>()
+                .unwrap(),
+            DeviceEndpoint::Unix(PathBuf::from("/tmp/deskassistant.sock"))
+        );
+        assert!("unix:".parse::<DeviceEndpoint>().is_err());
+        assert!("tcp:localhost".parse::<DeviceEndpoint>().is_err());
+    }
+}
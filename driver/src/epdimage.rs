@@ -1,79 +1,832 @@
 use std::path::Path;
 
+use image::{DynamicImage, GrayImage, ImageFormat, Rgb, RgbImage};
+use pyo3::prelude::*;
+
+use crate::{
+    adjust, fit, orientation, DisplayColors, Dithering, DriverError, FitMode, Gravity,
+    ResizeFilter, Rotation, EPD_HEIGHT, EPD_WIDTH,
+};
+
+/// The default threshold level for reducing the grayscale image to black and white
+pub const DEFAULT_THRESHOLD: u8 = 0x88;
+
 #[derive(Debug, Clone)]
 pub struct EpdImage {
     image: image::DynamicImage,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct EpdImageFormat {
     /// width in px
     pub width: u32,
     /// height in px
     pub height: u32,
+    /// With a third colour the image data consists of two planes, see [EpdImage::export]
+    pub colors: DisplayColors,
+    pub bit_depth: BitDepth,
+}
+
+/// The number of bits per pixel of the packed image data
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, num_derive::FromPrimitive,
+)]
+#[pyclass]
+pub enum BitDepth {
+    /// Black and white
+    #[default]
+    #[clap(name = "1")]
+    Bpp1 = 0,
+    /// Four gray levels
+    #[clap(name = "2")]
+    Bpp2 = 1,
+}
+
+impl BitDepth {
+    pub fn bits_per_pixel(self) -> usize {
+        match self {
+            BitDepth::Bpp1 => 1,
+            BitDepth::Bpp2 => 2,
+        }
+    }
+}
+
+/// Transferred as the number of bits per pixel minus one,
+/// so that the zeroed byte sent by older firmware means one bit per pixel
+impl From<BitDepth> for u8 {
+    fn from(bit_depth: BitDepth) -> Self {
+        bit_depth as u8
+    }
+}
+
+impl TryFrom<u8> for BitDepth {
+    type Error = DriverError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        num_traits::FromPrimitive::from_u8(value).ok_or(DriverError::InvalidBitDepth(value))
+    }
+}
+
+/// The format of the display, for devices that don't report it
+impl Default for EpdImageFormat {
+    fn default() -> Self {
+        Self {
+            width: EPD_WIDTH,
+            height: EPD_HEIGHT,
+            colors: DisplayColors::BlackWhite,
+            bit_depth: BitDepth::Bpp1,
+        }
+    }
+}
+
+impl EpdImageFormat {
+    /// The number of bytes of a packed plane
+    pub fn plane_len(&self) -> usize {
+        (self.width as usize * self.height as usize * self.bit_depth.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// The number of planes of the packed image data, two for displays with a third colour
+    pub fn planes(&self) -> usize {
+        match self.colors.accent() {
+            Some(_) => 2,
+            None => 1,
+        }
+    }
+
+    /// The number of bytes of the packed image data, without the padding byte
+    pub fn packed_len(&self) -> usize {
+        self.planes() * self.plane_len()
+    }
+}
+
+/// The memory layout of raw pixel data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[pyclass]
+pub enum PixelLayout {
+    Rgb8,
+    Rgba8,
+    /// Qt's `ARGB32` on little endian machines
+    Bgra8,
+    Luma8,
+}
+
+impl PixelLayout {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelLayout::Rgb8 => 3,
+            PixelLayout::Rgba8 | PixelLayout::Bgra8 => 4,
+            PixelLayout::Luma8 => 1,
+        }
+    }
+
+    /// Converts a pixel to rgb8, compositing its alpha onto the gray level `background`
+    fn to_rgb(self, px: &[u8], background: u8) -> [u8; 3] {
+        match self {
+            PixelLayout::Rgb8 => [px[0], px[1], px[2]],
+            PixelLayout::Rgba8 => [px[0], px[1], px[2]].map(|c| composite(c, px[3], background)),
+            PixelLayout::Bgra8 => [px[2], px[1], px[0]].map(|c| composite(c, px[3], background)),
+            PixelLayout::Luma8 => [px[0]; 3],
+        }
+    }
+}
+
+fn composite(channel: u8, alpha: u8, background: u8) -> u8 {
+    let (channel, alpha, background) = (channel as u32, alpha as u32, background as u32);
+    ((channel * alpha + background * (0xff - alpha) + 0x7f) / 0xff) as u8
+}
+
+/// Options for converting an image for display on the EPD
+#[derive(Debug, Clone, Copy, PartialEq, clap::Args)]
+#[pyclass]
+pub struct ExportOptions {
+    /// How the image is reduced to black and white, or the colours of the display
+    #[clap(long, value_enum, default_value_t)]
+    #[pyo3(get, set)]
+    pub dithering: Dithering,
+    /// How the image is fitted into the dimensions of the display
+    #[clap(long, value_enum, default_value_t)]
+    #[pyo3(get, set)]
+    pub fit: FitMode,
+    /// Where the image is anchored when it is letterboxed or cropped
+    #[clap(long, value_enum, default_value_t)]
+    #[pyo3(get, set)]
+    pub gravity: Gravity,
+    /// The gray level of the space not covered by the image, 0 is black and 255 white
+    #[clap(long, value_parser, default_value_t = 0xff)]
+    #[pyo3(get, set)]
+    pub fill_color: u8,
+    /// The filter used when resizing the image
+    #[clap(long, value_enum, default_value_t)]
+    #[pyo3(get, set)]
+    pub resize_filter: ResizeFilter,
+    /// Stretch the levels of the image to the full range
+    #[clap(long, action)]
+    #[pyo3(get, set)]
+    pub auto_levels: bool,
+    /// Added to every pixel, from -255 to 255
+    #[clap(long, value_parser, default_value_t = 0, allow_hyphen_values = true)]
+    #[pyo3(get, set)]
+    pub brightness: i16,
+    /// The factor the distance to mid-gray is scaled with, 1.0 leaves the contrast unchanged
+    #[clap(long, value_parser, default_value_t = 1.0)]
+    #[pyo3(get, set)]
+    pub contrast: f32,
+    /// Values above 1.0 brighten the midtones, values below darken them
+    #[clap(long, value_parser, default_value_t = 1.0)]
+    #[pyo3(get, set)]
+    pub gamma: f32,
+    /// Invert the image
+    #[clap(long, action)]
+    #[pyo3(get, set)]
+    pub invert: bool,
+    /// Pixels brighter than the threshold become white. Unused for displays with a third colour and two bits per pixel
+    #[clap(long, value_parser, default_value_t = DEFAULT_THRESHOLD)]
+    #[pyo3(get, set)]
+    pub threshold: u8,
+    /// Determine the threshold automatically with Otsu's method, overriding `threshold`
+    #[clap(long, action)]
+    #[pyo3(get, set)]
+    pub otsu_threshold: bool,
+    /// How far the image is rotated clockwise, for displays that are not mounted in landscape.
+    /// With 90 and 270 the image is fitted into the swapped dimensions of the display
+    #[clap(long, value_enum, default_value_t)]
+    #[pyo3(get, set)]
+    pub rotation: Rotation,
+    /// Mirror the image horizontally, before it is rotated
+    #[clap(long, action)]
+    #[pyo3(get, set)]
+    pub flip_horizontal: bool,
+    /// Mirror the image vertically, before it is rotated
+    #[clap(long, action)]
+    #[pyo3(get, set)]
+    pub flip_vertical: bool,
+    /// Two bits per pixel show four gray levels, if the device supports it.
+    /// Unsupported by displays with a third colour
+    #[clap(long, value_enum, default_value_t)]
+    #[pyo3(get, set)]
+    pub bit_depth: BitDepth,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            dithering: Dithering::default(),
+            fit: FitMode::default(),
+            gravity: Gravity::default(),
+            fill_color: 0xff,
+            resize_filter: ResizeFilter::default(),
+            auto_levels: false,
+            brightness: 0,
+            contrast: 1.0,
+            gamma: 1.0,
+            invert: false,
+            threshold: DEFAULT_THRESHOLD,
+            otsu_threshold: false,
+            rotation: Rotation::default(),
+            flip_horizontal: false,
+            flip_vertical: false,
+            bit_depth: BitDepth::default(),
+        }
+    }
+}
+
+#[pymethods]
+impl ExportOptions {
+    #[staticmethod]
+    pub fn new() -> Self {
+        Self::default()
+    }
 }
 
 impl EpdImage {
-    pub fn load_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, DriverError> {
         let image = image::io::Reader::open(path)?.decode()?;
 
         Ok(Self { image })
     }
 
+    /// Decodes an image that is encoded in any of the supported formats, e.g. PNG
+    pub fn load_from_memory(data: &[u8]) -> Result<Self, DriverError> {
+        let image = image::load_from_memory(data)?;
+
+        Ok(Self { image })
+    }
+
     /// Expects data in rgb8
-    pub fn load_from_data(width: u32, height: u32, data: Vec<u8>) -> anyhow::Result<Self> {
+    pub fn load_from_data(width: u32, height: u32, data: Vec<u8>) -> Result<Self, DriverError> {
+        if width == 0 || height == 0 {
+            return Err(DriverError::EmptyImage { width, height });
+        }
+
+        let len = data.len();
         let image_buf: image::RgbImage = image::ImageBuffer::from_vec(width, height, data)
-            .ok_or_else(|| anyhow::anyhow!("failed to create rgb image from data."))?;
+            .ok_or(DriverError::ImageDataSize { width, height, len })?;
 
         Ok(Self {
             image: image::DynamicImage::from(image_buf),
         })
     }
 
-    pub fn export(self, format: &EpdImageFormat) -> anyhow::Result<Vec<u8>> {
-        let mut data = vec![];
+    /// Loads raw pixel data in the given layout.
+    ///
+    /// `stride` is the number of bytes from the start of one line to the next, which may include padding.
+    /// Transparent pixels are composited onto the gray level `background`.
+    pub fn load_from_raw(
+        width: u32,
+        height: u32,
+        stride: usize,
+        layout: PixelLayout,
+        background: u8,
+        data: &[u8],
+    ) -> Result<Self, DriverError> {
+        if width == 0 || height == 0 {
+            return Err(DriverError::EmptyImage { width, height });
+        }
 
-        let grayimage = self
-            .image
-            .resize_exact(
+        let bytes_per_pixel = layout.bytes_per_pixel();
+        let line_len = width as usize * bytes_per_pixel;
+        if stride < line_len {
+            return Err(DriverError::InvalidStride { stride, line_len });
+        }
+
+        let len = data.len();
+        if len < stride * (height as usize - 1) + line_len {
+            return Err(DriverError::ImageDataSize { width, height, len });
+        }
+
+        let lines = data
+            .chunks(stride)
+            .take(height as usize)
+            .map(|line| &line[..line_len]);
+
+        // Can't fail, the number of pixels matches the dimensions
+        let image = match layout {
+            PixelLayout::Luma8 => DynamicImage::from(
+                GrayImage::from_vec(width, height, lines.flatten().copied().collect()).unwrap(),
+            ),
+            _ => DynamicImage::from(
+                RgbImage::from_vec(
+                    width,
+                    height,
+                    lines
+                        .flat_map(|line| line.chunks_exact(bytes_per_pixel))
+                        .flat_map(|px| layout.to_rgb(px, background))
+                        .collect(),
+                )
+                .unwrap(),
+            ),
+        };
+
+        Ok(Self { image })
+    }
+
+    /// Reconstructs the image that is shown on the EPD from the packed data produced by [EpdImage::export].
+    pub fn from_packed(data: &[u8], format: &EpdImageFormat) -> Result<Self, DriverError> {
+        if format.bit_depth == BitDepth::Bpp2 {
+            return Ok(Self {
+                image: image::DynamicImage::from(unpack_gray4(data, format.width, format.height)?),
+            });
+        }
+
+        let Some(accent) = format.colors.accent() else {
+            return Ok(Self {
+                image: image::DynamicImage::from(unpack(data, format.width, format.height)?),
+            });
+        };
+
+        let plane_len = format.plane_len();
+        if data.len() < 2 * plane_len {
+            return Err(DriverError::ImageDataSize {
+                width: format.width,
+                height: format.height,
+                len: data.len(),
+            });
+        }
+        let black = unpack(&data[..plane_len], format.width, format.height)?;
+        let color = unpack(&data[plane_len..], format.width, format.height)?;
+
+        Ok(Self {
+            image: image::DynamicImage::from(RgbImage::from_fn(
                 format.width,
                 format.height,
-                image::imageops::FilterType::Gaussian,
-            )
-            .grayscale()
-            .into_luma8();
+                |x, y| {
+                    if color.get_pixel(x, y).0[0] == 0x00 {
+                        Rgb(accent)
+                    } else if black.get_pixel(x, y).0[0] == 0x00 {
+                        Rgb([0x00; 3])
+                    } else {
+                        Rgb([0xff; 3])
+                    }
+                },
+            )),
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.image.width(), self.image.height())
+    }
 
-        let bwimage = imageproc::contrast::threshold(&grayimage, 0x88).into_raw();
-        let mut px_chunks = bwimage.chunks_exact(8);
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), DriverError> {
+        self.image.save(path)?;
+        Ok(())
+    }
 
-        // Pack the luma8 image (1byte per px) to one that only has one bit per pixel
-        for px_chunk in px_chunks.by_ref() {
-            let px = px_chunk[0] & 0x01 << 7
-                | px_chunk[1] & 0x01 << 6
-                | px_chunk[2] & 0x01 << 5
-                | px_chunk[3] & 0x01 << 4
-                | px_chunk[4] & 0x01 << 3
-                | px_chunk[5] & 0x01 << 2
-                | px_chunk[6] & 0x01 << 1
-                | px_chunk[7] & 0x01;
+    /// Encodes the image as PNG
+    pub fn encode_png(&self) -> Result<Vec<u8>, DriverError> {
+        let mut data = vec![];
+        self.image
+            .write_to(&mut std::io::Cursor::new(&mut data), ImageFormat::Png)?;
+        Ok(data)
+    }
 
-            data.push(px);
+    /// Converts the image for display on the EPD and packs it with one bit per pixel, see [pack].
+    /// The data has exactly the length [EpdImageFormat::packed_len], without the padding byte of the packing.
+    ///
+    /// For displays with a third colour the data consists of two planes of the length [EpdImageFormat::plane_len],
+    /// first the black and then the colour plane. In both a cleared bit marks a black or coloured pixel.
+    ///
+    /// With two bits per pixel the image is reduced to four gray levels instead, see [pack_gray4].
+    /// The bit depth is taken from the format, not from the options.
+    pub fn export(
+        self,
+        format: &EpdImageFormat,
+        options: &ExportOptions,
+    ) -> Result<Vec<u8>, DriverError> {
+        if format.bit_depth == BitDepth::Bpp2 && format.colors.accent().is_some() {
+            return Err(DriverError::InvalidExportOption(
+                "two bits per pixel are unsupported by displays with a third colour",
+            ));
         }
 
-        let mut remainder_chunk = px_chunks.remainder().to_vec();
-        remainder_chunk.resize(8, 0x00);
+        let (width, height) = options
+            .rotation
+            .logical_dimensions(format.width, format.height);
+
+        let fitted = fit::fit(
+            &self.image,
+            width,
+            height,
+            options.fit,
+            options.gravity,
+            options.fill_color,
+            options.resize_filter.into(),
+        );
+        let oriented = orientation::orient(
+            fitted,
+            options.rotation,
+            options.flip_horizontal,
+            options.flip_vertical,
+        );
 
-        let remainder_px = remainder_chunk[0] & 0x01 << 7
-            | remainder_chunk[1] & 0x01 << 6
-            | remainder_chunk[2] & 0x01 << 5
-            | remainder_chunk[3] & 0x01 << 4
-            | remainder_chunk[4] & 0x01 << 3
-            | remainder_chunk[5] & 0x01 << 2
-            | remainder_chunk[6] & 0x01 << 1
-            | remainder_chunk[7] & 0x01;
+        if let Some(accent) = format.colors.accent() {
+            return export_planes(oriented.into_rgb8(), accent, format, options);
+        }
+
+        let mut grayimage = oriented.grayscale().into_luma8();
+
+        let threshold = adjust::adjust(&mut grayimage, options)?;
+
+        if format.bit_depth == BitDepth::Bpp2 {
+            let grayimage = options.dithering.apply_levels(&grayimage, 4).into_raw();
+
+            let mut data = pack_gray4(&grayimage);
+            data.truncate(format.packed_len());
+            return Ok(data);
+        }
 
-        data.push(remainder_px);
+        let bwimage = options.dithering.apply(&grayimage, threshold).into_raw();
 
+        let mut data = pack(&bwimage);
+        data.truncate(format.packed_len());
         Ok(data)
     }
+
+    /// Runs the export pipeline and reconstructs the image exactly as it will be shown on the EPD.
+    ///
+    /// The preview is turned back into the orientation the display is viewed in.
+    pub fn preview(
+        self,
+        format: &EpdImageFormat,
+        options: &ExportOptions,
+    ) -> Result<Self, DriverError> {
+        let data = self.export(format, options)?;
+        let native = Self::from_packed(&data, format)?;
+
+        Ok(Self {
+            image: orientation::unorient(
+                native.image,
+                options.rotation,
+                options.flip_horizontal,
+                options.flip_vertical,
+            ),
+        })
+    }
+}
+
+/// Quantizes the image to black, white and the accent colour and packs it into a black and a colour plane
+fn export_planes(
+    mut rgbimage: RgbImage,
+    accent: [u8; 3],
+    format: &EpdImageFormat,
+    options: &ExportOptions,
+) -> Result<Vec<u8>, DriverError> {
+    const BLACK: usize = 0;
+    const ACCENT: usize = 2;
+
+    adjust::adjust_rgb(&mut rgbimage, options)?;
+
+    let indices = options
+        .dithering
+        .apply_palette(&rgbimage, &[[0x00; 3], [0xff; 3], accent]);
+
+    let black_plane = indices
+        .iter()
+        .map(|&i| if i == BLACK { 0x00 } else { 0xff })
+        .collect::<Vec<u8>>();
+    let color_plane = indices
+        .iter()
+        .map(|&i| if i == ACCENT { 0x00 } else { 0xff })
+        .collect::<Vec<u8>>();
+
+    // Both planes without their padding byte, so the colour plane starts right after the black plane
+    let mut data = pack(&black_plane);
+    data.truncate(format.plane_len());
+    data.extend_from_slice(&pack(&color_plane)[..format.plane_len()]);
+
+    Ok(data)
+}
+
+/// Packs a black and white luma8 image (1byte per px) to one that only has one bit per pixel.
+///
+/// Pixels with the highest bit set become a set bit. The last byte is always followed by a padding byte.
+pub fn pack(bwimage: &[u8]) -> Vec<u8> {
+    let pack_chunk = |px_chunk: &[u8]| {
+        px_chunk
+            .iter()
+            .enumerate()
+            .fold(0x00, |px, (i, bw)| px | (bw & 0x80) >> i)
+    };
+
+    let mut data = vec![];
+    let mut px_chunks = bwimage.chunks_exact(8);
+
+    for px_chunk in px_chunks.by_ref() {
+        data.push(pack_chunk(px_chunk));
+    }
+
+    let mut remainder_chunk = px_chunks.remainder().to_vec();
+    remainder_chunk.resize(8, 0x00);
+    data.push(pack_chunk(&remainder_chunk));
+
+    data
+}
+
+/// Inverts [pack], expanding every bit to a black (0x00) or white (0xff) pixel.
+pub fn unpack(data: &[u8], width: u32, height: u32) -> Result<GrayImage, DriverError> {
+    let px_cnt = width as usize * height as usize;
+
+    if data.len() * 8 < px_cnt {
+        return Err(DriverError::ImageDataSize {
+            width,
+            height,
+            len: data.len(),
+        });
+    }
+
+    let pixels = (0..px_cnt)
+        .map(|i| {
+            if data[i / 8] & (0x80 >> (i % 8)) != 0 {
+                0xff
+            } else {
+                0x00
+            }
+        })
+        .collect::<Vec<u8>>();
+
+    // Can't fail, the number of pixels matches the dimensions
+    Ok(GrayImage::from_vec(width, height, pixels).unwrap())
+}
+
+/// Packs a luma8 image with the gray levels 0x00, 0x55, 0xaa and 0xff to two bits per pixel.
+///
+/// The two highest bits of each pixel are kept, so white is `0b11`. The last byte is always followed by a padding byte.
+pub fn pack_gray4(grayimage: &[u8]) -> Vec<u8> {
+    let pack_chunk = |px_chunk: &[u8]| {
+        px_chunk[0] & 0xc0
+            | (px_chunk[1] & 0xc0) >> 2
+            | (px_chunk[2] & 0xc0) >> 4
+            | px_chunk[3] >> 6
+    };
+
+    let mut data = vec![];
+    let mut px_chunks = grayimage.chunks_exact(4);
+
+    for px_chunk in px_chunks.by_ref() {
+        data.push(pack_chunk(px_chunk));
+    }
+
+    let mut remainder_chunk = px_chunks.remainder().to_vec();
+    remainder_chunk.resize(4, 0x00);
+    data.push(pack_chunk(&remainder_chunk));
+
+    data
+}
+
+/// Inverts [pack_gray4], expanding every pixel to one of the gray levels 0x00, 0x55, 0xaa and 0xff.
+pub fn unpack_gray4(data: &[u8], width: u32, height: u32) -> Result<GrayImage, DriverError> {
+    let px_cnt = width as usize * height as usize;
+
+    if data.len() * 4 < px_cnt {
+        return Err(DriverError::ImageDataSize {
+            width,
+            height,
+            len: data.len(),
+        });
+    }
+
+    let pixels = (0..px_cnt)
+        .map(|i| (data[i / 4] >> (6 - 2 * (i % 4)) & 0b11) * 0x55)
+        .collect::<Vec<u8>>();
+
+    // Can't fail, the number of pixels matches the dimensions
+    Ok(GrayImage::from_vec(width, height, pixels).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::{pack, pack_gray4, unpack, unpack_gray4, BitDepth, PixelLayout};
+    use crate::{DisplayColors, DriverError, EpdImage, EpdImageFormat, ExportOptions, Rotation};
+
+    #[test]
+    fn pack_unpack_roundtrip() {
+        for (width, height) in [(400, 300), (7, 3), (1, 1), (9, 2)] {
+            let image = GrayImage::from_fn(width, height, |x, y| {
+                if (x * 3 + y * 5) % 7 < 3 {
+                    Luma([0xff])
+                } else {
+                    Luma([0x00])
+                }
+            });
+
+            let packed = pack(image.as_raw());
+            assert_eq!(packed.len(), (width * height) as usize / 8 + 1);
+
+            assert_eq!(unpack(&packed, width, height).unwrap(), image);
+        }
+    }
+
+    #[test]
+    fn pack_unpack_gray4_roundtrip() {
+        for (width, height) in [(400, 300), (7, 3), (1, 1), (9, 2)] {
+            let image =
+                GrayImage::from_fn(width, height, |x, y| Luma([((x + y * 3) % 4) as u8 * 0x55]));
+
+            let packed = pack_gray4(image.as_raw());
+            assert_eq!(packed.len(), (width * height) as usize / 4 + 1);
+
+            assert_eq!(unpack_gray4(&packed, width, height).unwrap(), image);
+        }
+    }
+
+    #[test]
+    fn gray4_preview() {
+        let image = EpdImage {
+            image: image::DynamicImage::from(GrayImage::from_fn(40, 30, |x, _| {
+                Luma([(x / 10) as u8 * 0x55])
+            })),
+        };
+        let format = EpdImageFormat {
+            width: 40,
+            height: 30,
+            bit_depth: BitDepth::Bpp2,
+            ..Default::default()
+        };
+
+        let data = image
+            .clone()
+            .export(&format, &ExportOptions::default())
+            .unwrap();
+        assert_eq!(data.len(), format.packed_len());
+
+        let preview = image
+            .preview(&format, &ExportOptions::default())
+            .unwrap()
+            .image
+            .into_luma8();
+        for (x, level) in [(5, 0x00), (15, 0x55), (25, 0xaa), (35, 0xff)] {
+            assert_eq!(preview.get_pixel(x, 15).0[0], level);
+        }
+
+        let format = EpdImageFormat {
+            colors: DisplayColors::BlackWhiteRed,
+            ..format
+        };
+        assert!(EpdImage::load_from_data(1, 1, vec![0x00; 3])
+            .unwrap()
+            .export(&format, &ExportOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    fn pack_keeps_highest_bit() {
+        let bwimage = [0x80, 0x7f, 0xc0, 0x01, 0xff, 0x00, 0xfe, 0x40, 0x81];
+
+        assert_eq!(pack(&bwimage), vec![0b1010_1010, 0b1000_0000]);
+    }
+
+    #[test]
+    fn unpack_too_short() {
+        assert!(unpack(&[0xff; 14], 10, 12).is_err());
+        assert!(unpack(&[0xff; 15], 10, 12).is_ok());
+    }
+
+    #[test]
+    fn preview_has_export_dimensions() {
+        let image = EpdImage {
+            image: image::DynamicImage::from(GrayImage::from_pixel(64, 64, Luma([0x40]))),
+        };
+        let format = EpdImageFormat {
+            width: 40,
+            height: 30,
+            ..Default::default()
+        };
+
+        let preview = image
+            .clone()
+            .preview(&format, &ExportOptions::default())
+            .unwrap();
+        assert_eq!(preview.dimensions(), (40, 30));
+
+        let options = ExportOptions {
+            rotation: Rotation::Rotate90,
+            ..Default::default()
+        };
+        let preview = image.preview(&format, &options).unwrap();
+        assert_eq!(preview.dimensions(), (30, 40));
+    }
+
+    #[test]
+    fn portrait_export_is_rotated() {
+        // top half black, bottom half white, authored in portrait
+        let image = EpdImage {
+            image: image::DynamicImage::from(GrayImage::from_fn(30, 40, |_, y| {
+                if y < 20 {
+                    Luma([0x00])
+                } else {
+                    Luma([0xff])
+                }
+            })),
+        };
+        let format = EpdImageFormat {
+            width: 40,
+            height: 30,
+            ..Default::default()
+        };
+        let options = ExportOptions {
+            rotation: Rotation::Rotate90,
+            ..Default::default()
+        };
+
+        let data = image.export(&format, &options).unwrap();
+        let native = unpack(&data, format.width, format.height).unwrap();
+
+        // rotated clockwise, the top half ends up on the right
+        assert_eq!(native.get_pixel(0, 15).0[0], 0xff);
+        assert_eq!(native.get_pixel(39, 15).0[0], 0x00);
+    }
+
+    #[test]
+    fn tri_color_export() {
+        // left third black, middle third red, right third white
+        let image = EpdImage {
+            image: image::DynamicImage::from(image::RgbImage::from_fn(32, 10, |x, _| match x {
+                0..=9 => image::Rgb([0x00, 0x00, 0x00]),
+                10..=19 => image::Rgb([0xe0, 0x10, 0x10]),
+                _ => image::Rgb([0xff, 0xff, 0xff]),
+            })),
+        };
+        let format = EpdImageFormat {
+            width: 32,
+            height: 10,
+            colors: DisplayColors::BlackWhiteRed,
+            ..Default::default()
+        };
+
+        let data = image
+            .clone()
+            .export(&format, &ExportOptions::default())
+            .unwrap();
+        // 320 px fill the last byte of each plane, neither plane keeps the padding byte of pack
+        assert_eq!(data.len(), format.packed_len());
+
+        let black = unpack(&data[..format.plane_len()], 32, 10).unwrap();
+        let color = unpack(&data[format.plane_len()..], 32, 10).unwrap();
+        assert_eq!(black.get_pixel(5, 5).0[0], 0x00);
+        assert_eq!(color.get_pixel(5, 5).0[0], 0xff);
+        assert_eq!(black.get_pixel(15, 5).0[0], 0xff);
+        assert_eq!(color.get_pixel(15, 5).0[0], 0x00);
+        assert_eq!(black.get_pixel(25, 5).0[0], 0xff);
+        assert_eq!(color.get_pixel(25, 5).0[0], 0xff);
+
+        let preview = image
+            .preview(&format, &ExportOptions::default())
+            .unwrap()
+            .image
+            .into_rgb8();
+        assert_eq!(preview.get_pixel(5, 5).0, [0x00, 0x00, 0x00]);
+        assert_eq!(preview.get_pixel(15, 5).0, [0xff, 0x00, 0x00]);
+        assert_eq!(preview.get_pixel(25, 5).0, [0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn raw_layouts() {
+        // 2x1 image with a stride of 12 bytes, pixels are red and half transparent blue
+        let rgba = [
+            0xff, 0x00, 0x00, 0xff, 0x00, 0x00, 0xff, 0x80, 0xaa, 0xaa, 0xaa, 0xaa,
+        ];
+        let bgra = [
+            0x00, 0x00, 0xff, 0xff, 0xff, 0x00, 0x00, 0x80, 0xaa, 0xaa, 0xaa, 0xaa,
+        ];
+        let expected =
+            image::RgbImage::from_vec(2, 1, vec![0xff, 0x00, 0x00, 0x7f, 0x7f, 0xff]).unwrap();
+
+        for (layout, data) in [(PixelLayout::Rgba8, rgba), (PixelLayout::Bgra8, bgra)] {
+            let image = EpdImage::load_from_raw(2, 1, 12, layout, 0xff, &data).unwrap();
+            assert_eq!(image.image.to_rgb8(), expected, "{layout:?}");
+        }
+
+        let luma = [0x10, 0x20, 0x00, 0x30, 0x40, 0x00];
+        let image = EpdImage::load_from_raw(2, 2, 3, PixelLayout::Luma8, 0xff, &luma).unwrap();
+        assert_eq!(
+            image.image.to_luma8().into_raw(),
+            vec![0x10, 0x20, 0x30, 0x40]
+        );
+
+        let rgb = [0x01, 0x02, 0x03];
+        let image = EpdImage::load_from_raw(1, 1, 3, PixelLayout::Rgb8, 0xff, &rgb).unwrap();
+        assert_eq!(image.image.to_rgb8().into_raw(), rgb.to_vec());
+    }
+
+    #[test]
+    fn raw_invalid_size() {
+        // the padding of the last line may be omitted
+        assert!(EpdImage::load_from_raw(2, 2, 4, PixelLayout::Luma8, 0xff, &[0x00; 6]).is_ok());
+        assert!(EpdImage::load_from_raw(2, 2, 4, PixelLayout::Luma8, 0xff, &[0x00; 5]).is_err());
+        assert!(EpdImage::load_from_raw(2, 2, 1, PixelLayout::Luma8, 0xff, &[0x00; 8]).is_err());
+
+        assert!(matches!(
+            EpdImage::load_from_raw(0, 2, 0, PixelLayout::Luma8, 0xff, &[]),
+            Err(DriverError::EmptyImage { .. })
+        ));
+        assert!(matches!(
+            EpdImage::load_from_raw(2, 0, 2, PixelLayout::Luma8, 0xff, &[]),
+            Err(DriverError::EmptyImage { .. })
+        ));
+        assert!(matches!(
+            EpdImage::load_from_data(0, 0, vec![]),
+            Err(DriverError::EmptyImage { .. })
+        ));
+    }
 }
@@ -1,6 +1,121 @@
+use std::path::PathBuf;
 
 #[derive(Debug, thiserror::Error)]
 pub enum DriverError {
-    #[error("Other error")]
-    Other
-}
\ No newline at end of file
+    #[error("Device not connected.")]
+    NotConnected,
+    #[error("usb transfer timed out")]
+    UsbTimeout,
+    #[error("usb endpoint stalled")]
+    UsbPipe,
+    #[error("usb error: {0}")]
+    Usb(rusb::Error),
+    #[error("io error: {0}")]
+    Io(std::io::Error),
+    #[error("received unexpected message `{actual}`, expected `{expected}`")]
+    UnexpectedMessage {
+        expected: &'static str,
+        actual: &'static str,
+    },
+    #[error("invalid message variant: `{0}`")]
+    InvalidMessageVariant(u8),
+    #[error("invalid page value: `{0}`")]
+    InvalidPage(u8),
+    #[error("invalid display colors value: `{0}`")]
+    InvalidDisplayColors(u8),
+    #[error("invalid bit depth value: `{0}`")]
+    InvalidBitDepth(u8),
+    #[error("invalid encoding value: `{0}`")]
+    InvalidEncoding(u8),
+    #[error("invalid encoded data: {0}")]
+    InvalidEncodedData(&'static str),
+    #[error("invalid transfer status value: `{0}`")]
+    InvalidTransferStatus(u8),
+    #[error("the device received corrupted data, the checksum does not match")]
+    ChecksumMismatch,
+    #[error("received {len} bytes of data, expected {expected}")]
+    TransferTooShort { expected: usize, len: usize },
+    #[error("invalid device endpoint `{0}`, expected `usb` or `unix:<path>`")]
+    InvalidDeviceEndpoint(String),
+    #[error("`{0}` does not exist")]
+    FileNotFound(PathBuf),
+    #[error("the device has no such image stored")]
+    ImageNotFound,
+    #[error("image decoding or encoding failed: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("image data with length {len} does not match the dimensions {width}x{height}")]
+    ImageDataSize { width: u32, height: u32, len: usize },
+    #[error("image dimensions {width}x{height} are empty")]
+    EmptyImage { width: u32, height: u32 },
+    #[error("image stride {stride} is shorter than a line of {line_len} bytes")]
+    InvalidStride { stride: usize, line_len: usize },
+    #[error("region {width}x{height} at ({x}, {y}) exceeds the display")]
+    InvalidRegion {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    #[error("invalid export option: {0}")]
+    InvalidExportOption(&'static str),
+    #[error("invalid settings json: {0}")]
+    SettingsJson(#[from] serde_json::Error),
+    #[error("invalid setting: {0}")]
+    InvalidSetting(String),
+    #[error("`{command}` is unsupported by firmware {firmware}")]
+    Unsupported {
+        command: &'static str,
+        firmware: String,
+    },
+    #[error("string encoding failed: {0}")]
+    StringEncoding(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<rusb::Error> for DriverError {
+    fn from(e: rusb::Error) -> Self {
+        match e {
+            rusb::Error::Timeout => Self::UsbTimeout,
+            rusb::Error::Pipe => Self::UsbPipe,
+            rusb::Error::NoDevice => Self::NotConnected,
+            e => Self::Usb(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for DriverError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            // Socket read and write timeouts are reported as either of these, depending on the platform
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Self::UsbTimeout,
+            // The stand-in device went away
+            std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset => Self::NotConnected,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl From<std::ffi::NulError> for DriverError {
+    fn from(e: std::ffi::NulError) -> Self {
+        Self::StringEncoding(Box::new(e))
+    }
+}
+
+impl From<std::ffi::FromVecWithNulError> for DriverError {
+    fn from(e: std::ffi::FromVecWithNulError) -> Self {
+        Self::StringEncoding(Box::new(e))
+    }
+}
+
+impl From<std::ffi::IntoStringError> for DriverError {
+    fn from(e: std::ffi::IntoStringError) -> Self {
+        Self::StringEncoding(Box::new(e))
+    }
+}
+
+impl From<std::string::FromUtf8Error> for DriverError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        Self::StringEncoding(Box::new(e))
+    }
+}
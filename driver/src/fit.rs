@@ -0,0 +1,224 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use pyo3::prelude::*;
+
+/// How an image is fitted into the dimensions of the display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[pyclass]
+pub enum FitMode {
+    /// Scale to the exact dimensions, distorting the aspect ratio
+    #[default]
+    Stretch,
+    /// Scale to fit entirely, filling the remaining space
+    Contain,
+    /// Scale to cover entirely, cropping what does not fit
+    Cover,
+    /// Place without scaling, cropping what does not fit and filling the remaining space
+    Center,
+}
+
+/// Where the image is anchored when it is letterboxed or cropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[pyclass]
+pub enum Gravity {
+    #[default]
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Gravity {
+    /// The horizontal and vertical alignment, 0.0 being left/top and 1.0 right/bottom
+    fn alignment(self) -> (f64, f64) {
+        match self {
+            Gravity::Center => (0.5, 0.5),
+            Gravity::Top => (0.5, 0.0),
+            Gravity::Bottom => (0.5, 1.0),
+            Gravity::Left => (0.0, 0.5),
+            Gravity::Right => (1.0, 0.5),
+            Gravity::TopLeft => (0.0, 0.0),
+            Gravity::TopRight => (1.0, 0.0),
+            Gravity::BottomLeft => (0.0, 1.0),
+            Gravity::BottomRight => (1.0, 1.0),
+        }
+    }
+
+    /// The offset of the image on the canvas. Negative if the image is larger than the canvas
+    fn offset(self, canvas: (u32, u32), image: (u32, u32)) -> (i64, i64) {
+        let (align_x, align_y) = self.alignment();
+
+        (
+            ((canvas.0 as i64 - image.0 as i64) as f64 * align_x).round() as i64,
+            ((canvas.1 as i64 - image.1 as i64) as f64 * align_y).round() as i64,
+        )
+    }
+}
+
+/// Fits the image into the given dimensions.
+///
+/// `fill` is the gray level of the space that is not covered by the image.
+pub fn fit(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    mode: FitMode,
+    gravity: Gravity,
+    fill: u8,
+    filter: FilterType,
+) -> DynamicImage {
+    let (image_width, image_height) = image.dimensions();
+    let scale_x = width as f64 / image_width as f64;
+    let scale_y = height as f64 / image_height as f64;
+
+    let scaled = match mode {
+        FitMode::Stretch => return image.resize_exact(width, height, filter),
+        FitMode::Contain => scale(image, scale_x.min(scale_y), filter),
+        FitMode::Cover => scale(image, scale_x.max(scale_y), filter),
+        FitMode::Center => image.clone(),
+    };
+
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba([fill, fill, fill, 0xff]));
+    let (x, y) = gravity.offset((width, height), scaled.dimensions());
+    image::imageops::overlay(&mut canvas, &scaled.to_rgba8(), x, y);
+
+    DynamicImage::from(canvas)
+}
+
+fn scale(image: &DynamicImage, factor: f64, filter: FilterType) -> DynamicImage {
+    let width = ((image.width() as f64 * factor).round() as u32).max(1);
+    let height = ((image.height() as f64 * factor).round() as u32).max(1);
+
+    image.resize_exact(width, height, filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::imageops::FilterType;
+    use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+    use super::{fit, FitMode, Gravity};
+
+    /// A black image with the given dimensions
+    fn black(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::from(RgbaImage::from_pixel(
+            width,
+            height,
+            Rgba([0x00, 0x00, 0x00, 0xff]),
+        ))
+    }
+
+    fn is_black(image: &DynamicImage, x: u32, y: u32) -> bool {
+        image.get_pixel(x, y).0[0] == 0x00
+    }
+
+    #[test]
+    fn all_modes_produce_requested_dimensions() {
+        for mode in [
+            FitMode::Stretch,
+            FitMode::Contain,
+            FitMode::Cover,
+            FitMode::Center,
+        ] {
+            let fitted = fit(
+                &black(123, 45),
+                400,
+                300,
+                mode,
+                Gravity::Center,
+                0xff,
+                FilterType::Nearest,
+            );
+            assert_eq!(fitted.dimensions(), (400, 300), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn contain_letterboxes() {
+        // 2:1 into 4:3 leaves bars at the top and bottom
+        let fitted = fit(
+            &black(200, 100),
+            400,
+            300,
+            FitMode::Contain,
+            Gravity::Center,
+            0xff,
+            FilterType::Nearest,
+        );
+
+        assert!(!is_black(&fitted, 200, 0));
+        assert!(!is_black(&fitted, 200, 49));
+        assert!(is_black(&fitted, 0, 50));
+        assert!(is_black(&fitted, 399, 249));
+        assert!(!is_black(&fitted, 200, 250));
+
+        let fitted = fit(
+            &black(200, 100),
+            400,
+            300,
+            FitMode::Contain,
+            Gravity::Top,
+            0xff,
+            FilterType::Nearest,
+        );
+        assert!(is_black(&fitted, 200, 0));
+        assert!(!is_black(&fitted, 200, 200));
+    }
+
+    #[test]
+    fn cover_crops_with_gravity() {
+        // left half black, right half white
+        let image = DynamicImage::from(RgbaImage::from_fn(200, 100, |x, _| {
+            if x < 100 {
+                Rgba([0x00, 0x00, 0x00, 0xff])
+            } else {
+                Rgba([0xff, 0xff, 0xff, 0xff])
+            }
+        }));
+
+        let left = fit(
+            &image,
+            300,
+            300,
+            FitMode::Cover,
+            Gravity::Left,
+            0xff,
+            FilterType::Nearest,
+        );
+        assert!(left.pixels().all(|(_, _, px)| px.0[0] == 0x00));
+
+        let right = fit(
+            &image,
+            300,
+            300,
+            FitMode::Cover,
+            Gravity::Right,
+            0xff,
+            FilterType::Nearest,
+        );
+        assert!(right.pixels().all(|(_, _, px)| px.0[0] == 0xff));
+    }
+
+    #[test]
+    fn center_does_not_scale() {
+        let fitted = fit(
+            &black(10, 10),
+            400,
+            300,
+            FitMode::Center,
+            Gravity::Center,
+            0xff,
+            FilterType::Nearest,
+        );
+
+        let black_px = fitted.pixels().filter(|(_, _, px)| px.0[0] == 0x00).count();
+        assert_eq!(black_px, 100);
+        assert!(is_black(&fitted, 195, 145));
+        assert!(is_black(&fitted, 204, 154));
+    }
+}
@@ -1,24 +1,72 @@
+pub mod actions;
+pub mod adjust;
+pub mod capabilities;
+pub mod compression;
 pub mod connection;
+pub mod dithering;
+pub mod endpoint;
 pub mod epdimage;
+pub mod error;
+pub mod fit;
 pub mod messages;
-pub mod actions;
+pub mod orientation;
 pub mod pybindings;
+pub mod region;
+pub mod settings;
+pub mod simulator;
+#[cfg(unix)]
+pub mod socket;
+pub mod transport;
 
 // Re-Exports
+pub use adjust::ResizeFilter;
+pub use capabilities::Capabilities;
+pub use capabilities::DisplayColors;
+pub use capabilities::Features;
+pub use compression::Encoding;
 pub use connection::UsbConnection;
+pub use dithering::Dithering;
+pub use endpoint::DeviceConnection;
+pub use endpoint::DeviceEndpoint;
+pub use epdimage::BitDepth;
 pub use epdimage::EpdImage;
 pub use epdimage::EpdImageFormat;
+pub use epdimage::ExportOptions;
+pub use epdimage::PixelLayout;
+pub use error::DriverError;
+pub use fit::FitMode;
+pub use fit::Gravity;
 pub use messages::DeviceMessage;
 pub use messages::HostMessage;
+pub use messages::TransferStatus;
+pub use orientation::Rotation;
+pub use region::PackedImage;
+pub use region::Region;
+pub use settings::DeviceSettings;
+pub use transport::Transport;
 
 use pyo3::prelude::*;
 
+/// The width of the display, for devices that don't report it
 pub const EPD_WIDTH: u32 = 400;
+/// The height of the display, for devices that don't report it
 pub const EPD_HEIGHT: u32 = 300;
 
+/// The version of the protocol implemented by the host
+pub const PROTOCOL_VERSION: u8 = 3;
+
 #[derive(
-    Debug, Clone, Copy, clap::ValueEnum, num_derive::FromPrimitive, num_derive::ToPrimitive,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    num_derive::FromPrimitive,
+    serde::Serialize,
+    serde::Deserialize,
 )]
+#[serde(rename_all = "kebab-case")]
 #[pyclass]
 pub enum EpdPage {
     Overview = 0,
@@ -26,28 +74,67 @@ pub enum EpdPage {
     UserImage = 2,
 }
 
-impl TryFrom<EpdPage> for u8 {
-    type Error = anyhow::Error;
-
-    fn try_from(page: EpdPage) -> Result<Self, Self::Error> {
-        num_traits::ToPrimitive::to_u8(&page)
-            .ok_or_else(|| anyhow::anyhow!("u8 try_from::<Page>() for page {:?} failed.", page))
+impl From<EpdPage> for u8 {
+    fn from(page: EpdPage) -> Self {
+        page as u8
     }
 }
 
 impl TryFrom<u8> for EpdPage {
-    type Error = anyhow::Error;
+    type Error = DriverError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        num_traits::FromPrimitive::from_u8(value)
-            .ok_or_else(|| anyhow::anyhow!("Page try_from::<u8>() for value {} failed.", value))
+        num_traits::FromPrimitive::from_u8(value).ok_or(DriverError::InvalidPage(value))
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The status reported by the device.
+///
+/// Older firmware only reports the current page, all other fields are `None` then.
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[pyclass]
 pub struct DeviceStatus {
-    #[allow(unused)]
     #[pyo3(get, set)]
-    current_epd_page: EpdPage,
+    pub current_epd_page: EpdPage,
+    /// The protocol version implemented by the firmware, 0 if it only reports the current page
+    #[pyo3(get, set)]
+    pub protocol_version: u8,
+    /// major, minor, patch
+    #[pyo3(get, set)]
+    pub firmware_version: Option<(u8, u8, u8)>,
+    #[pyo3(get, set)]
+    pub display_width: Option<u16>,
+    #[pyo3(get, set)]
+    pub display_height: Option<u16>,
+    /// Free space on the mSD in KiB
+    #[pyo3(get, set)]
+    pub storage_free_kib: Option<u32>,
+    /// Size of the mSD in KiB
+    #[pyo3(get, set)]
+    pub storage_total_kib: Option<u32>,
+    #[pyo3(get, set)]
+    pub app_image_cnt: Option<u16>,
+    /// The app whose image is currently displayed
+    #[pyo3(get, set)]
+    pub displayed_app: Option<String>,
+    #[pyo3(get, set)]
+    pub uptime_s: Option<u32>,
+}
+
+impl DeviceStatus {
+    /// The status as reported by firmware that only reports the current page
+    pub fn short(current_epd_page: EpdPage) -> Self {
+        Self {
+            current_epd_page,
+            protocol_version: 0,
+            firmware_version: None,
+            display_width: None,
+            display_height: None,
+            storage_free_kib: None,
+            storage_total_kib: None,
+            app_image_cnt: None,
+            displayed_app: None,
+            uptime_s: None,
+        }
+    }
 }
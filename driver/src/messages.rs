@@ -1,29 +1,126 @@
 use crate::connection::{USB_DEVICE_MSG_LEN, USB_HOST_MSG_LEN};
-use crate::{DeviceStatus, EpdImageFormat, EpdPage};
+use crate::{
+    BitDepth, DeviceStatus, DisplayColors, DriverError, Encoding, EpdImageFormat, EpdPage, Features,
+};
 
-#[derive(Debug, Clone)]
+/// The offset of the displayed app name in the device status message. It is preceded by its length
+const STATUS_DISPLAYED_APP_OFFSET: usize = 25;
+/// The maximum length of the displayed app name in the device status message, longer names are truncated
+pub const STATUS_DISPLAYED_APP_MAX_LEN: usize = USB_DEVICE_MSG_LEN - STATUS_DISPLAYED_APP_OFFSET;
+
+/// Whether the device received a transfer intact
+#[derive(Debug, Clone, Copy, PartialEq, Eq, num_derive::FromPrimitive)]
+pub enum TransferStatus {
+    Ok = 0,
+    /// The CRC32 in the DataComplete message does not match the received data, the transfer was discarded
+    ChecksumMismatch = 1,
+}
+
+impl From<TransferStatus> for u8 {
+    fn from(status: TransferStatus) -> Self {
+        status as u8
+    }
+}
+
+impl TryFrom<u8> for TransferStatus {
+    type Error = DriverError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        num_traits::FromPrimitive::from_u8(value).ok_or(DriverError::InvalidTransferStatus(value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HostMessage {
     Data {
         data: [u8; USB_HOST_MSG_LEN - 1],
     },
-    DataComplete,
+    /// Ends a transfer. `crc32` is the CRC32 of its data if the length was declared with DataLength,
+    /// otherwise of the payloads of all its data messages
+    DataComplete {
+        crc32: u32,
+    },
+    /// Declares the exact length of the data in the following data messages, if the device supports [Features::TRANSFER_LENGTH]
+    DataLength {
+        len: u32,
+    },
     RequestDeviceStatus,
     RefreshDisplay,
     SwitchPage(EpdPage),
+    /// Followed by the packed image data, encoded with `encoding`, in data messages
     UpdateUserImage {
         format: EpdImageFormat,
+        encoding: Encoding,
     },
+    /// Followed by the app name string and then the packed image data, encoded with `encoding`, each in data messages
     UpdateAppImage {
         app_name_str_len: u16,
         format: EpdImageFormat,
+        encoding: Encoding,
     },
     ReportActiveApp {
         str_len: u16,
     },
     RequestListAppImages,
+    RequestUserImage,
+    /// Followed by the app name string in data messages
+    RequestAppImage {
+        app_name_str_len: u16,
+    },
+    /// Followed by the app name string in data messages
+    DeleteAppImage {
+        app_name_str_len: u16,
+    },
+    /// Followed by the current and then the new app name string, each in data messages
+    RenameAppImage {
+        app_name_str_len: u16,
+        new_app_name_str_len: u16,
+    },
+    DeleteAllAppImages,
+    RequestSettings,
+    /// Followed by the settings JSON string in data messages
+    UpdateSettings {
+        str_len: u16,
+    },
+    /// Starts the handshake, answered with the capabilities of the device
+    Hello {
+        protocol_version: u8,
+    },
+    /// Replaces the region at `x`, `y` with the size of the format in the stored user image.
+    /// Followed by the packed image data of the region, encoded with `encoding`, in data messages
+    UpdateRegion {
+        x: u16,
+        y: u16,
+        format: EpdImageFormat,
+        encoding: Encoding,
+    },
 }
 
 impl HostMessage {
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            HostMessage::Data { .. } => "Data",
+            HostMessage::DataComplete { .. } => "DataComplete",
+            HostMessage::DataLength { .. } => "DataLength",
+            HostMessage::RequestDeviceStatus => "RequestDeviceStatus",
+            HostMessage::RefreshDisplay => "RefreshDisplay",
+            HostMessage::SwitchPage(_) => "SwitchPage",
+            HostMessage::UpdateUserImage { .. } => "UpdateUserImage",
+            HostMessage::UpdateAppImage { .. } => "UpdateAppImage",
+            HostMessage::ReportActiveApp { .. } => "ReportActiveApp",
+            HostMessage::RequestListAppImages => "RequestListAppImages",
+            HostMessage::RequestUserImage => "RequestUserImage",
+            HostMessage::RequestAppImage { .. } => "RequestAppImage",
+            HostMessage::DeleteAppImage { .. } => "DeleteAppImage",
+            HostMessage::RenameAppImage { .. } => "RenameAppImage",
+            HostMessage::DeleteAllAppImages => "DeleteAllAppImages",
+            HostMessage::RequestSettings => "RequestSettings",
+            HostMessage::UpdateSettings { .. } => "UpdateSettings",
+            HostMessage::Hello { .. } => "Hello",
+            HostMessage::UpdateRegion { .. } => "UpdateRegion",
+        }
+    }
+
     pub fn into_data(self) -> [u8; USB_HOST_MSG_LEN] {
         let mut msg_data: [u8; USB_HOST_MSG_LEN] = [0; USB_HOST_MSG_LEN];
 
@@ -32,12 +129,13 @@ impl HostMessage {
                 msg_data[0] = 0x00; // Host message variant
 
                 // Copy the data into the msg
-                for (to, from) in msg_data.iter_mut().skip(1).zip(data.into_iter()) {
+                for (to, from) in msg_data.iter_mut().skip(1).zip(data) {
                     *to = from;
                 }
             }
-            HostMessage::DataComplete => {
+            HostMessage::DataComplete { crc32 } => {
                 msg_data[0] = 0x01; // Host message variant
+                msg_data[1..5].copy_from_slice(&crc32.to_be_bytes());
             }
             HostMessage::RequestDeviceStatus => {
                 msg_data[0] = 0x02; // Host message variant
@@ -47,18 +145,22 @@ impl HostMessage {
             }
             HostMessage::SwitchPage(page) => {
                 msg_data[0] = 0x04; // Host message variant
-                msg_data[1] = page.try_into().unwrap();
+                msg_data[1] = page.into();
             }
-            HostMessage::UpdateUserImage { format } => {
+            HostMessage::UpdateUserImage { format, encoding } => {
                 msg_data[0] = 0x05; // Host message variant
                 msg_data[1] = ((format.width >> 8) & 0xff) as u8;
                 msg_data[2] = (format.width & 0xff) as u8;
                 msg_data[3] = ((format.height >> 8) & 0xff) as u8;
                 msg_data[4] = (format.height & 0xff) as u8;
+                msg_data[5] = format.colors.into();
+                msg_data[6] = format.bit_depth.into();
+                msg_data[7] = encoding.into();
             }
             HostMessage::UpdateAppImage {
                 app_name_str_len,
                 format,
+                encoding,
             } => {
                 msg_data[0] = 0x06; // Host message variant
                 msg_data[1] = ((format.width >> 8) & 0xff) as u8;
@@ -67,6 +169,9 @@ impl HostMessage {
                 msg_data[4] = (format.height & 0xff) as u8;
                 msg_data[5] = ((app_name_str_len >> 8) & 0xff) as u8;
                 msg_data[6] = (app_name_str_len & 0xff) as u8;
+                msg_data[7] = format.colors.into();
+                msg_data[8] = format.bit_depth.into();
+                msg_data[9] = encoding.into();
             }
             HostMessage::ReportActiveApp { str_len } => {
                 msg_data[0] = 0x07; // Host message variant
@@ -76,37 +181,641 @@ impl HostMessage {
             HostMessage::RequestListAppImages => {
                 msg_data[0] = 0x08; // Host message variant
             }
+            HostMessage::RequestUserImage => {
+                msg_data[0] = 0x09; // Host message variant
+            }
+            HostMessage::RequestAppImage { app_name_str_len } => {
+                msg_data[0] = 0x0a; // Host message variant
+                msg_data[1] = ((app_name_str_len >> 8) & 0xff) as u8;
+                msg_data[2] = (app_name_str_len & 0xff) as u8;
+            }
+            HostMessage::DeleteAppImage { app_name_str_len } => {
+                msg_data[0] = 0x0b; // Host message variant
+                msg_data[1] = ((app_name_str_len >> 8) & 0xff) as u8;
+                msg_data[2] = (app_name_str_len & 0xff) as u8;
+            }
+            HostMessage::RenameAppImage {
+                app_name_str_len,
+                new_app_name_str_len,
+            } => {
+                msg_data[0] = 0x0c; // Host message variant
+                msg_data[1] = ((app_name_str_len >> 8) & 0xff) as u8;
+                msg_data[2] = (app_name_str_len & 0xff) as u8;
+                msg_data[3] = ((new_app_name_str_len >> 8) & 0xff) as u8;
+                msg_data[4] = (new_app_name_str_len & 0xff) as u8;
+            }
+            HostMessage::DeleteAllAppImages => {
+                msg_data[0] = 0x0d; // Host message variant
+            }
+            HostMessage::RequestSettings => {
+                msg_data[0] = 0x0e; // Host message variant
+            }
+            HostMessage::UpdateSettings { str_len } => {
+                msg_data[0] = 0x0f; // Host message variant
+                msg_data[1] = ((str_len >> 8) & 0xff) as u8;
+                msg_data[2] = (str_len & 0xff) as u8;
+            }
+            HostMessage::Hello { protocol_version } => {
+                msg_data[0] = 0x10; // Host message variant
+                msg_data[1] = protocol_version;
+            }
+            HostMessage::UpdateRegion {
+                x,
+                y,
+                format,
+                encoding,
+            } => {
+                msg_data[0] = 0x11; // Host message variant
+                msg_data[1] = ((x >> 8) & 0xff) as u8;
+                msg_data[2] = (x & 0xff) as u8;
+                msg_data[3] = ((y >> 8) & 0xff) as u8;
+                msg_data[4] = (y & 0xff) as u8;
+                msg_data[5] = ((format.width >> 8) & 0xff) as u8;
+                msg_data[6] = (format.width & 0xff) as u8;
+                msg_data[7] = ((format.height >> 8) & 0xff) as u8;
+                msg_data[8] = (format.height & 0xff) as u8;
+                msg_data[9] = format.colors.into();
+                msg_data[10] = format.bit_depth.into();
+                msg_data[11] = encoding.into();
+            }
+            HostMessage::DataLength { len } => {
+                msg_data[0] = 0x12; // Host message variant
+                msg_data[1..5].copy_from_slice(&len.to_be_bytes());
+            }
         }
 
         msg_data
     }
+
+    pub fn from_data(data: &[u8; USB_HOST_MSG_LEN]) -> Result<Self, DriverError> {
+        match data[0] {
+            0x00 => Ok(Self::Data {
+                data: data[1..USB_HOST_MSG_LEN].try_into().unwrap(),
+            }),
+            0x01 => Ok(Self::DataComplete {
+                crc32: u32::from_be_bytes(data[1..5].try_into().unwrap()),
+            }),
+            0x02 => Ok(Self::RequestDeviceStatus),
+            0x03 => Ok(Self::RefreshDisplay),
+            0x04 => Ok(Self::SwitchPage(EpdPage::try_from(data[1])?)),
+            0x05 => Ok(Self::UpdateUserImage {
+                format: EpdImageFormat {
+                    width: (data[1] as u32) << 8 | data[2] as u32,
+                    height: (data[3] as u32) << 8 | data[4] as u32,
+                    colors: DisplayColors::try_from(data[5])?,
+                    bit_depth: BitDepth::try_from(data[6])?,
+                },
+                encoding: Encoding::try_from(data[7])?,
+            }),
+            0x06 => Ok(Self::UpdateAppImage {
+                app_name_str_len: (data[5] as u16) << 8 | data[6] as u16,
+                format: EpdImageFormat {
+                    width: (data[1] as u32) << 8 | data[2] as u32,
+                    height: (data[3] as u32) << 8 | data[4] as u32,
+                    colors: DisplayColors::try_from(data[7])?,
+                    bit_depth: BitDepth::try_from(data[8])?,
+                },
+                encoding: Encoding::try_from(data[9])?,
+            }),
+            0x07 => Ok(Self::ReportActiveApp {
+                str_len: (data[1] as u16) << 8 | data[2] as u16,
+            }),
+            0x08 => Ok(Self::RequestListAppImages),
+            0x09 => Ok(Self::RequestUserImage),
+            0x0a => Ok(Self::RequestAppImage {
+                app_name_str_len: (data[1] as u16) << 8 | data[2] as u16,
+            }),
+            0x0b => Ok(Self::DeleteAppImage {
+                app_name_str_len: (data[1] as u16) << 8 | data[2] as u16,
+            }),
+            0x0c => Ok(Self::RenameAppImage {
+                app_name_str_len: (data[1] as u16) << 8 | data[2] as u16,
+                new_app_name_str_len: (data[3] as u16) << 8 | data[4] as u16,
+            }),
+            0x0d => Ok(Self::DeleteAllAppImages),
+            0x0e => Ok(Self::RequestSettings),
+            0x0f => Ok(Self::UpdateSettings {
+                str_len: (data[1] as u16) << 8 | data[2] as u16,
+            }),
+            0x10 => Ok(Self::Hello {
+                protocol_version: data[1],
+            }),
+            0x11 => Ok(Self::UpdateRegion {
+                x: (data[1] as u16) << 8 | data[2] as u16,
+                y: (data[3] as u16) << 8 | data[4] as u16,
+                format: EpdImageFormat {
+                    width: (data[5] as u32) << 8 | data[6] as u32,
+                    height: (data[7] as u32) << 8 | data[8] as u32,
+                    colors: DisplayColors::try_from(data[9])?,
+                    bit_depth: BitDepth::try_from(data[10])?,
+                },
+                encoding: Encoding::try_from(data[11])?,
+            }),
+            0x12 => Ok(Self::DataLength {
+                len: u32::from_be_bytes(data[1..5].try_into().unwrap()),
+            }),
+            variant => Err(DriverError::InvalidMessageVariant(variant)),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DeviceMessage {
-    Data { data: [u8; USB_HOST_MSG_LEN - 1] },
+    Data {
+        data: [u8; USB_HOST_MSG_LEN - 1],
+    },
     DataComplete,
     DeviceStatus(DeviceStatus),
-    ListAppImages { str_len: u16 },
+    ListAppImages {
+        str_len: u16,
+    },
+    /// Followed by the packed image data in data messages
+    StoredImage {
+        format: EpdImageFormat,
+    },
+    /// Answers an image request when the device has no such image stored
+    ImageNotFound,
+    /// Followed by the settings JSON string in data messages
+    Settings {
+        str_len: u16,
+    },
+    /// The answer to the handshake
+    Capabilities {
+        protocol_version: u8,
+        firmware_version: (u8, u8, u8),
+        features: Features,
+        /// 0 if the firmware does not know the size of its display
+        display_width: u16,
+        display_height: u16,
+        display_colors: DisplayColors,
+    },
+    /// Acknowledges a transfer of the host, if the device supports [Features::CHECKSUM]
+    TransferAck(TransferStatus),
+    /// Declares the exact length of the data in the following data messages.
+    /// Firmware that predates it pads the last data message instead
+    DataLength {
+        len: u32,
+    },
 }
 
 impl DeviceMessage {
-    pub fn from_data(data: &[u8; USB_DEVICE_MSG_LEN]) -> anyhow::Result<Self> {
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            DeviceMessage::Data { .. } => "Data",
+            DeviceMessage::DataComplete => "DataComplete",
+            DeviceMessage::DeviceStatus(_) => "DeviceStatus",
+            DeviceMessage::ListAppImages { .. } => "ListAppImages",
+            DeviceMessage::StoredImage { .. } => "StoredImage",
+            DeviceMessage::ImageNotFound => "ImageNotFound",
+            DeviceMessage::Settings { .. } => "Settings",
+            DeviceMessage::Capabilities { .. } => "Capabilities",
+            DeviceMessage::TransferAck(_) => "TransferAck",
+            DeviceMessage::DataLength { .. } => "DataLength",
+        }
+    }
+
+    pub fn into_data(self) -> [u8; USB_DEVICE_MSG_LEN] {
+        let mut msg_data: [u8; USB_DEVICE_MSG_LEN] = [0; USB_DEVICE_MSG_LEN];
+
+        match self {
+            DeviceMessage::Data { data } => {
+                msg_data[0] = 0x00; // Device message variant
+
+                // Copy the data into the msg
+                for (to, from) in msg_data.iter_mut().skip(1).zip(data) {
+                    *to = from;
+                }
+            }
+            DeviceMessage::DataComplete => {
+                msg_data[0] = 0x01; // Device message variant
+            }
+            DeviceMessage::DeviceStatus(status) => {
+                msg_data[0] = 0x02; // Device message variant
+                msg_data[1] = status.current_epd_page.into();
+
+                // The short form only contains the page
+                if status.protocol_version != 0 {
+                    let (major, minor, patch) = status.firmware_version.unwrap_or_default();
+                    msg_data[2] = major;
+                    msg_data[3] = minor;
+                    msg_data[4] = patch;
+                    msg_data[5] = status.protocol_version;
+                    msg_data[6..8]
+                        .copy_from_slice(&status.display_width.unwrap_or_default().to_be_bytes());
+                    msg_data[8..10]
+                        .copy_from_slice(&status.display_height.unwrap_or_default().to_be_bytes());
+                    msg_data[10..14].copy_from_slice(
+                        &status.storage_free_kib.unwrap_or_default().to_be_bytes(),
+                    );
+                    msg_data[14..18].copy_from_slice(
+                        &status.storage_total_kib.unwrap_or_default().to_be_bytes(),
+                    );
+                    msg_data[18..20]
+                        .copy_from_slice(&status.app_image_cnt.unwrap_or_default().to_be_bytes());
+                    msg_data[20..24]
+                        .copy_from_slice(&status.uptime_s.unwrap_or_default().to_be_bytes());
+
+                    let displayed_app = status.displayed_app.unwrap_or_default();
+                    let displayed_app = truncate_str(&displayed_app, STATUS_DISPLAYED_APP_MAX_LEN);
+                    msg_data[24] = displayed_app.len() as u8;
+                    msg_data[STATUS_DISPLAYED_APP_OFFSET..][..displayed_app.len()]
+                        .copy_from_slice(displayed_app.as_bytes());
+                }
+            }
+            DeviceMessage::ListAppImages { str_len } => {
+                msg_data[0] = 0x03; // Device message variant
+                msg_data[1] = ((str_len >> 8) & 0xff) as u8;
+                msg_data[2] = (str_len & 0xff) as u8;
+            }
+            DeviceMessage::StoredImage { format } => {
+                msg_data[0] = 0x04; // Device message variant
+                msg_data[1] = ((format.width >> 8) & 0xff) as u8;
+                msg_data[2] = (format.width & 0xff) as u8;
+                msg_data[3] = ((format.height >> 8) & 0xff) as u8;
+                msg_data[4] = (format.height & 0xff) as u8;
+                msg_data[5] = format.colors.into();
+                msg_data[6] = format.bit_depth.into();
+            }
+            DeviceMessage::ImageNotFound => {
+                msg_data[0] = 0x05; // Device message variant
+            }
+            DeviceMessage::Settings { str_len } => {
+                msg_data[0] = 0x06; // Device message variant
+                msg_data[1] = ((str_len >> 8) & 0xff) as u8;
+                msg_data[2] = (str_len & 0xff) as u8;
+            }
+            DeviceMessage::Capabilities {
+                protocol_version,
+                firmware_version: (major, minor, patch),
+                features,
+                display_width,
+                display_height,
+                display_colors,
+            } => {
+                msg_data[0] = 0x07; // Device message variant
+                msg_data[1] = protocol_version;
+                msg_data[2] = major;
+                msg_data[3] = minor;
+                msg_data[4] = patch;
+                msg_data[5..9].copy_from_slice(&features.0.to_be_bytes());
+                msg_data[9..11].copy_from_slice(&display_width.to_be_bytes());
+                msg_data[11..13].copy_from_slice(&display_height.to_be_bytes());
+                msg_data[13] = display_colors.into();
+            }
+            DeviceMessage::TransferAck(status) => {
+                msg_data[0] = 0x08; // Device message variant
+                msg_data[1] = status.into();
+            }
+            DeviceMessage::DataLength { len } => {
+                msg_data[0] = 0x09; // Device message variant
+                msg_data[1..5].copy_from_slice(&len.to_be_bytes());
+            }
+        }
+
+        msg_data
+    }
+
+    pub fn from_data(data: &[u8; USB_DEVICE_MSG_LEN]) -> Result<Self, DriverError> {
         match data[0] {
             0x00 => Ok(Self::Data {
                 data: data[1..USB_DEVICE_MSG_LEN].try_into().unwrap(),
             }),
             0x01 => Ok(Self::DataComplete),
-            0x02 => Ok(Self::DeviceStatus(DeviceStatus {
-                current_epd_page: EpdPage::try_from(data[1])?,
-            })),
+            0x02 => Ok(Self::DeviceStatus(device_status_from_data(data)?)),
             0x03 => Ok(Self::ListAppImages {
                 str_len: (data[1] as u16) << 8 | data[2] as u16,
             }),
-            variant => Err(anyhow::anyhow!(
-                "Could not extract DeviceMessage from data, invalid message variant: `{}`",
-                variant
-            )),
+            0x04 => Ok(Self::StoredImage {
+                format: EpdImageFormat {
+                    width: (data[1] as u32) << 8 | data[2] as u32,
+                    height: (data[3] as u32) << 8 | data[4] as u32,
+                    colors: DisplayColors::try_from(data[5])?,
+                    bit_depth: BitDepth::try_from(data[6])?,
+                },
+            }),
+            0x05 => Ok(Self::ImageNotFound),
+            0x06 => Ok(Self::Settings {
+                str_len: (data[1] as u16) << 8 | data[2] as u16,
+            }),
+            0x07 => Ok(Self::Capabilities {
+                protocol_version: data[1],
+                firmware_version: (data[2], data[3], data[4]),
+                features: Features(u32::from_be_bytes(data[5..9].try_into().unwrap())),
+                display_width: u16::from_be_bytes([data[9], data[10]]),
+                display_height: u16::from_be_bytes([data[11], data[12]]),
+                display_colors: DisplayColors::try_from(data[13])?,
+            }),
+            0x08 => Ok(Self::TransferAck(TransferStatus::try_from(data[1])?)),
+            0x09 => Ok(Self::DataLength {
+                len: u32::from_be_bytes(data[1..5].try_into().unwrap()),
+            }),
+            variant => Err(DriverError::InvalidMessageVariant(variant)),
         }
     }
 }
+
+fn device_status_from_data(data: &[u8; USB_DEVICE_MSG_LEN]) -> Result<DeviceStatus, DriverError> {
+    let current_epd_page = EpdPage::try_from(data[1])?;
+    let protocol_version = data[5];
+
+    // Older firmware only sends the page, leaving the rest zeroed
+    if protocol_version == 0 {
+        return Ok(DeviceStatus::short(current_epd_page));
+    }
+
+    let displayed_app_len = (data[24] as usize).min(STATUS_DISPLAYED_APP_MAX_LEN);
+    let displayed_app =
+        String::from_utf8(data[STATUS_DISPLAYED_APP_OFFSET..][..displayed_app_len].to_vec())?;
+
+    Ok(DeviceStatus {
+        current_epd_page,
+        protocol_version,
+        firmware_version: Some((data[2], data[3], data[4])),
+        display_width: Some(u16::from_be_bytes([data[6], data[7]])),
+        display_height: Some(u16::from_be_bytes([data[8], data[9]])),
+        storage_free_kib: Some(u32::from_be_bytes(data[10..14].try_into().unwrap())),
+        storage_total_kib: Some(u32::from_be_bytes(data[14..18].try_into().unwrap())),
+        app_image_cnt: Some(u16::from_be_bytes([data[18], data[19]])),
+        displayed_app: (!displayed_app.is_empty()).then_some(displayed_app),
+        uptime_s: Some(u32::from_be_bytes(data[20..24].try_into().unwrap())),
+    })
+}
+
+/// Truncates the string to at most `max_len` bytes, without splitting a character
+fn truncate_str(s: &str, max_len: usize) -> &str {
+    let mut len = s.len().min(max_len);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    &s[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{DeviceMessage, HostMessage, TransferStatus, STATUS_DISPLAYED_APP_MAX_LEN};
+    use crate::connection::{USB_DEVICE_MSG_LEN, USB_HOST_MSG_LEN};
+    use crate::{
+        BitDepth, DeviceStatus, DisplayColors, DriverError, Encoding, EpdImageFormat, EpdPage,
+        Features,
+    };
+
+    fn epd_page() -> impl Strategy<Value = EpdPage> {
+        prop_oneof![
+            Just(EpdPage::Overview),
+            Just(EpdPage::AppScreen),
+            Just(EpdPage::UserImage),
+        ]
+    }
+
+    fn display_colors() -> impl Strategy<Value = DisplayColors> {
+        prop_oneof![
+            Just(DisplayColors::BlackWhite),
+            Just(DisplayColors::BlackWhiteRed),
+            Just(DisplayColors::BlackWhiteYellow),
+        ]
+    }
+
+    fn encoding() -> impl Strategy<Value = Encoding> {
+        prop_oneof![Just(Encoding::Raw), Just(Encoding::Rle), Just(Encoding::Lz)]
+    }
+
+    /// Width and height are transferred as u16
+    fn epd_image_format() -> impl Strategy<Value = EpdImageFormat> {
+        let dimension = prop_oneof![Just(0), Just(1), Just(u16::MAX as u32), 0..=u16::MAX as u32,];
+
+        (
+            dimension.clone(),
+            dimension,
+            display_colors(),
+            prop_oneof![Just(BitDepth::Bpp1), Just(BitDepth::Bpp2)],
+        )
+            .prop_map(|(width, height, colors, bit_depth)| EpdImageFormat {
+                width,
+                height,
+                colors,
+                bit_depth,
+            })
+    }
+
+    fn str_len() -> impl Strategy<Value = u16> {
+        prop_oneof![Just(0), Just(1), Just(u16::MAX), any::<u16>()]
+    }
+
+    fn data_payload<const N: usize>() -> impl Strategy<Value = [u8; N]> {
+        prop::collection::vec(any::<u8>(), N).prop_map(|data| data.try_into().unwrap())
+    }
+
+    /// The full form of the device status
+    fn device_status() -> impl Strategy<Value = DeviceStatus> {
+        (
+            epd_page(),
+            1..=u8::MAX,
+            any::<(u8, u8, u8)>(),
+            any::<(u16, u16, u32, u32, u16, u32)>(),
+            prop_oneof![
+                Just(None),
+                "[a-z-]{1,39}".prop_map(Some),
+                Just(Some("a".repeat(STATUS_DISPLAYED_APP_MAX_LEN)))
+            ],
+        )
+            .prop_map(
+                |(
+                    current_epd_page,
+                    protocol_version,
+                    firmware_version,
+                    (width, height, storage_free, storage_total, app_image_cnt, uptime),
+                    displayed_app,
+                )| DeviceStatus {
+                    current_epd_page,
+                    protocol_version,
+                    firmware_version: Some(firmware_version),
+                    display_width: Some(width),
+                    display_height: Some(height),
+                    storage_free_kib: Some(storage_free),
+                    storage_total_kib: Some(storage_total),
+                    app_image_cnt: Some(app_image_cnt),
+                    displayed_app,
+                    uptime_s: Some(uptime),
+                },
+            )
+    }
+
+    fn host_message() -> impl Strategy<Value = HostMessage> {
+        prop_oneof![
+            data_payload::<{ USB_HOST_MSG_LEN - 1 }>().prop_map(|data| HostMessage::Data { data }),
+            any::<u32>().prop_map(|crc32| HostMessage::DataComplete { crc32 }),
+            Just(HostMessage::RequestDeviceStatus),
+            Just(HostMessage::RefreshDisplay),
+            epd_page().prop_map(HostMessage::SwitchPage),
+            (epd_image_format(), encoding())
+                .prop_map(|(format, encoding)| HostMessage::UpdateUserImage { format, encoding }),
+            (str_len(), epd_image_format(), encoding()).prop_map(
+                |(app_name_str_len, format, encoding)| HostMessage::UpdateAppImage {
+                    app_name_str_len,
+                    format,
+                    encoding,
+                }
+            ),
+            str_len().prop_map(|str_len| HostMessage::ReportActiveApp { str_len }),
+            Just(HostMessage::RequestListAppImages),
+            Just(HostMessage::RequestUserImage),
+            str_len()
+                .prop_map(|app_name_str_len| HostMessage::RequestAppImage { app_name_str_len }),
+            str_len().prop_map(|app_name_str_len| HostMessage::DeleteAppImage { app_name_str_len }),
+            (str_len(), str_len()).prop_map(|(app_name_str_len, new_app_name_str_len)| {
+                HostMessage::RenameAppImage {
+                    app_name_str_len,
+                    new_app_name_str_len,
+                }
+            }),
+            Just(HostMessage::DeleteAllAppImages),
+            Just(HostMessage::RequestSettings),
+            str_len().prop_map(|str_len| HostMessage::UpdateSettings { str_len }),
+            any::<u8>().prop_map(|protocol_version| HostMessage::Hello { protocol_version }),
+            (any::<u16>(), any::<u16>(), epd_image_format(), encoding()).prop_map(
+                |(x, y, format, encoding)| HostMessage::UpdateRegion {
+                    x,
+                    y,
+                    format,
+                    encoding,
+                }
+            ),
+            any::<u32>().prop_map(|len| HostMessage::DataLength { len }),
+        ]
+    }
+
+    fn device_message() -> impl Strategy<Value = DeviceMessage> {
+        prop_oneof![
+            data_payload::<{ USB_DEVICE_MSG_LEN - 1 }>()
+                .prop_map(|data| DeviceMessage::Data { data }),
+            Just(DeviceMessage::DataComplete),
+            epd_page().prop_map(|page| DeviceMessage::DeviceStatus(DeviceStatus::short(page))),
+            device_status().prop_map(DeviceMessage::DeviceStatus),
+            str_len().prop_map(|str_len| DeviceMessage::ListAppImages { str_len }),
+            epd_image_format().prop_map(|format| DeviceMessage::StoredImage { format }),
+            Just(DeviceMessage::ImageNotFound),
+            str_len().prop_map(|str_len| DeviceMessage::Settings { str_len }),
+            (
+                any::<u8>(),
+                any::<(u8, u8, u8)>(),
+                any::<u32>(),
+                any::<(u16, u16)>(),
+                display_colors()
+            )
+                .prop_map(
+                    |(
+                        protocol_version,
+                        firmware_version,
+                        features,
+                        (display_width, display_height),
+                        display_colors,
+                    )| DeviceMessage::Capabilities {
+                        protocol_version,
+                        firmware_version,
+                        features: Features(features),
+                        display_width,
+                        display_height,
+                        display_colors,
+                    }
+                ),
+            prop_oneof![
+                Just(TransferStatus::Ok),
+                Just(TransferStatus::ChecksumMismatch)
+            ]
+            .prop_map(DeviceMessage::TransferAck),
+            any::<u32>().prop_map(|len| DeviceMessage::DataLength { len }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn host_message_roundtrip(msg in host_message()) {
+            prop_assert_eq!(HostMessage::from_data(&msg.clone().into_data()).unwrap(), msg);
+        }
+
+        #[test]
+        fn device_message_roundtrip(msg in device_message()) {
+            prop_assert_eq!(DeviceMessage::from_data(&msg.clone().into_data()).unwrap(), msg);
+        }
+
+        #[test]
+        fn host_message_invalid_variant(variant in 0x13_u8..) {
+            let mut data = [0; USB_HOST_MSG_LEN];
+            data[0] = variant;
+            prop_assert!(matches!(
+                HostMessage::from_data(&data),
+                Err(DriverError::InvalidMessageVariant(v)) if v == variant
+            ));
+        }
+
+        #[test]
+        fn device_message_invalid_variant(variant in 0x0a_u8..) {
+            let mut data = [0; USB_DEVICE_MSG_LEN];
+            data[0] = variant;
+            prop_assert!(matches!(
+                DeviceMessage::from_data(&data),
+                Err(DriverError::InvalidMessageVariant(v)) if v == variant
+            ));
+        }
+    }
+
+    #[test]
+    fn invalid_page() {
+        let mut data = [0; USB_HOST_MSG_LEN];
+        data[0] = 0x04;
+        data[1] = 0x03;
+        assert!(matches!(
+            HostMessage::from_data(&data),
+            Err(DriverError::InvalidPage(0x03))
+        ));
+
+        let mut data = [0; USB_DEVICE_MSG_LEN];
+        data[0] = 0x02;
+        data[1] = 0xff;
+        assert!(matches!(
+            DeviceMessage::from_data(&data),
+            Err(DriverError::InvalidPage(0xff))
+        ));
+    }
+
+    #[test]
+    fn invalid_display_colors() {
+        let mut data = [0; USB_DEVICE_MSG_LEN];
+        data[0] = 0x07;
+        data[13] = 0x03;
+        assert!(matches!(
+            DeviceMessage::from_data(&data),
+            Err(DriverError::InvalidDisplayColors(0x03))
+        ));
+    }
+
+    #[test]
+    fn short_device_status() {
+        // Sent by firmware that only knows about the page
+        let mut data = [0; USB_DEVICE_MSG_LEN];
+        data[0] = 0x02;
+        data[1] = 0x01;
+        assert_eq!(
+            DeviceMessage::from_data(&data).unwrap(),
+            DeviceMessage::DeviceStatus(DeviceStatus::short(EpdPage::AppScreen))
+        );
+    }
+
+    #[test]
+    fn device_status_truncates_displayed_app() {
+        let mut status = DeviceStatus::short(EpdPage::AppScreen);
+        status.protocol_version = 1;
+        status.displayed_app = Some("ä".repeat(STATUS_DISPLAYED_APP_MAX_LEN));
+
+        let DeviceMessage::DeviceStatus(decoded) =
+            DeviceMessage::from_data(&DeviceMessage::DeviceStatus(status).into_data()).unwrap()
+        else {
+            panic!("expected a device status");
+        };
+        let displayed_app = decoded.displayed_app.unwrap();
+        assert!(displayed_app.len() <= STATUS_DISPLAYED_APP_MAX_LEN);
+        assert!(displayed_app.chars().all(|c| c == 'ä'));
+    }
+}
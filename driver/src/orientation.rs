@@ -0,0 +1,137 @@
+use image::DynamicImage;
+use pyo3::prelude::*;
+
+/// How far the image is rotated clockwise to match the way the display is mounted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[pyclass]
+pub enum Rotation {
+    #[default]
+    #[clap(name = "0")]
+    Rotate0,
+    #[clap(name = "90")]
+    Rotate90,
+    #[clap(name = "180")]
+    Rotate180,
+    #[clap(name = "270")]
+    Rotate270,
+}
+
+impl Rotation {
+    /// Whether width and height are swapped
+    pub fn is_portrait(self) -> bool {
+        matches!(self, Rotation::Rotate90 | Rotation::Rotate270)
+    }
+
+    /// The dimensions of the image before it is rotated, given the native dimensions of the display
+    pub fn logical_dimensions(self, width: u32, height: u32) -> (u32, u32) {
+        if self.is_portrait() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+}
+
+/// Flips and then rotates the image from the layout it is authored in into the native layout of the display
+pub fn orient(
+    image: DynamicImage,
+    rotation: Rotation,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+) -> DynamicImage {
+    let image = if flip_horizontal {
+        image.fliph()
+    } else {
+        image
+    };
+    let image = if flip_vertical { image.flipv() } else { image };
+
+    match rotation {
+        Rotation::Rotate0 => image,
+        Rotation::Rotate90 => image.rotate90(),
+        Rotation::Rotate180 => image.rotate180(),
+        Rotation::Rotate270 => image.rotate270(),
+    }
+}
+
+/// Inverts [orient], turning the native layout of the display back into the one the image is authored in
+pub fn unorient(
+    image: DynamicImage,
+    rotation: Rotation,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+) -> DynamicImage {
+    let image = match rotation {
+        Rotation::Rotate0 => image,
+        Rotation::Rotate90 => image.rotate270(),
+        Rotation::Rotate180 => image.rotate180(),
+        Rotation::Rotate270 => image.rotate90(),
+    };
+
+    let image = if flip_vertical { image.flipv() } else { image };
+    if flip_horizontal {
+        image.fliph()
+    } else {
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GenericImageView, GrayImage, Luma};
+
+    use super::{orient, unorient, Rotation};
+
+    /// A 3x2 image where every pixel has a distinct value
+    fn numbered() -> DynamicImage {
+        DynamicImage::from(GrayImage::from_fn(3, 2, |x, y| Luma([(y * 3 + x) as u8])))
+    }
+
+    fn px(image: &DynamicImage, x: u32, y: u32) -> u8 {
+        image.get_pixel(x, y).0[0]
+    }
+
+    #[test]
+    fn rotate_clockwise() {
+        let rotated = orient(numbered(), Rotation::Rotate90, false, false);
+        assert_eq!(rotated.dimensions(), (2, 3));
+        // the bottom left corner ends up top left
+        assert_eq!(px(&rotated, 0, 0), 3);
+        assert_eq!(px(&rotated, 1, 0), 0);
+
+        let rotated = orient(numbered(), Rotation::Rotate270, false, false);
+        assert_eq!(rotated.dimensions(), (2, 3));
+        // the top right corner ends up top left
+        assert_eq!(px(&rotated, 0, 0), 2);
+    }
+
+    #[test]
+    fn flip() {
+        let flipped = orient(numbered(), Rotation::Rotate0, true, false);
+        assert_eq!(px(&flipped, 0, 0), 2);
+
+        let flipped = orient(numbered(), Rotation::Rotate0, false, true);
+        assert_eq!(px(&flipped, 0, 0), 3);
+    }
+
+    #[test]
+    fn unorient_roundtrip() {
+        for rotation in [
+            Rotation::Rotate0,
+            Rotation::Rotate90,
+            Rotation::Rotate180,
+            Rotation::Rotate270,
+        ] {
+            for (flip_horizontal, flip_vertical) in
+                [(false, false), (true, false), (false, true), (true, true)]
+            {
+                let oriented = orient(numbered(), rotation, flip_horizontal, flip_vertical);
+                assert_eq!(
+                    unorient(oriented, rotation, flip_horizontal, flip_vertical).to_luma8(),
+                    numbered().to_luma8(),
+                    "{rotation:?} {flip_horizontal} {flip_vertical}"
+                );
+            }
+        }
+    }
+}
@@ -1,37 +1,284 @@
 use std::path::PathBuf;
 use std::time::Duration;
 
+use pyo3::exceptions::PyTypeError;
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 
-use crate::{actions, DeviceStatus, EpdPage, UsbConnection};
+use crate::{
+    actions, BitDepth, Capabilities, DeviceConnection, DeviceEndpoint, DeviceSettings,
+    DeviceStatus, DisplayColors, Dithering, DriverError, EpdImage, EpdImageFormat, EpdPage,
+    ExportOptions, FitMode, Gravity, PackedImage, PixelLayout, Region, ResizeFilter, Rotation,
+    Transport, UsbConnection,
+};
+
+/// The exceptions raised by the python module
+pub mod exceptions {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyException;
+
+    // The base class of all errors raised by the driver
+    create_exception!(deskassistant_driver, DriverError, PyException);
+    // The device is not connected or went away. Recoverable by reconnecting
+    create_exception!(deskassistant_driver, DeviceNotConnectedError, DriverError);
+    // The device did not answer in time. Recoverable by retrying
+    create_exception!(deskassistant_driver, DeviceTimeoutError, DriverError);
+    // The device sent or expected something that does not match the protocol
+    create_exception!(deskassistant_driver, ProtocolError, DriverError);
+    // The firmware of the device does not support the command
+    create_exception!(deskassistant_driver, UnsupportedError, DriverError);
+    // The image could not be loaded or converted
+    create_exception!(deskassistant_driver, ImageError, DriverError);
+}
 
 #[pymodule]
-fn deskassistant_driver(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+fn deskassistant_driver(py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyUsbConnection>()?;
+    m.add_class::<PyEpdImage>()?;
     m.add_class::<DeviceStatus>()?;
+    m.add_class::<Capabilities>()?;
+    m.add_class::<DisplayColors>()?;
     m.add_class::<EpdPage>()?;
+    m.add_class::<Dithering>()?;
+    m.add_class::<FitMode>()?;
+    m.add_class::<Gravity>()?;
+    m.add_class::<ResizeFilter>()?;
+    m.add_class::<Rotation>()?;
+    m.add_class::<BitDepth>()?;
+    m.add_class::<PixelLayout>()?;
+    m.add_class::<Region>()?;
+    m.add_class::<ExportOptions>()?;
+    m.add_function(wrap_pyfunction!(preview_image_from_file, m)?)?;
+    m.add_function(wrap_pyfunction!(preview_image, m)?)?;
+
+    m.add("DriverError", py.get_type::<exceptions::DriverError>())?;
+    m.add(
+        "DeviceNotConnectedError",
+        py.get_type::<exceptions::DeviceNotConnectedError>(),
+    )?;
+    m.add(
+        "DeviceTimeoutError",
+        py.get_type::<exceptions::DeviceTimeoutError>(),
+    )?;
+    m.add("ProtocolError", py.get_type::<exceptions::ProtocolError>())?;
+    m.add(
+        "UnsupportedError",
+        py.get_type::<exceptions::UnsupportedError>(),
+    )?;
+    m.add("ImageError", py.get_type::<exceptions::ImageError>())?;
 
     Ok(())
 }
 
+impl From<DriverError> for PyErr {
+    fn from(e: DriverError) -> Self {
+        let msg = e.to_string();
+
+        match e {
+            DriverError::NotConnected => exceptions::DeviceNotConnectedError::new_err(msg),
+            DriverError::UsbTimeout => exceptions::DeviceTimeoutError::new_err(msg),
+            DriverError::UsbPipe
+            | DriverError::UnexpectedMessage { .. }
+            | DriverError::InvalidMessageVariant(_)
+            | DriverError::InvalidPage(_)
+            | DriverError::InvalidDisplayColors(_)
+            | DriverError::InvalidBitDepth(_)
+            | DriverError::InvalidEncoding(_)
+            | DriverError::InvalidEncodedData(_)
+            | DriverError::InvalidTransferStatus(_)
+            | DriverError::ChecksumMismatch
+            | DriverError::TransferTooShort { .. } => exceptions::ProtocolError::new_err(msg),
+            DriverError::Unsupported { .. } => exceptions::UnsupportedError::new_err(msg),
+            DriverError::FileNotFound(_)
+            | DriverError::ImageNotFound
+            | DriverError::Image(_)
+            | DriverError::ImageDataSize { .. }
+            | DriverError::EmptyImage { .. }
+            | DriverError::InvalidStride { .. }
+            | DriverError::InvalidRegion { .. }
+            | DriverError::InvalidExportOption(_) => exceptions::ImageError::new_err(msg),
+            DriverError::Usb(_)
+            | DriverError::Io(_)
+            | DriverError::InvalidDeviceEndpoint(_)
+            | DriverError::SettingsJson(_)
+            | DriverError::InvalidSetting(_)
+            | DriverError::StringEncoding(_) => exceptions::DriverError::new_err(msg),
+        }
+    }
+}
+
+/// Accepts a [PyEpdImage], encoded image data as `bytes` (e.g. PNG), a PIL image or a QImage.
+/// Transparent pixels of PIL images and QImages are composited onto white
+impl<'source> FromPyObject<'source> for EpdImage {
+    fn extract(ob: &'source PyAny) -> PyResult<Self> {
+        if let Ok(image) = ob.extract::<PyRef<PyEpdImage>>() {
+            return Ok(image.0.clone());
+        }
+
+        extract_image(ob, 0xff)
+    }
+}
+
+/// Extracts the image from `bytes`, a PIL image or a QImage,
+/// compositing transparent pixels onto the gray level `background`
+fn extract_image(ob: &PyAny, background: u8) -> PyResult<EpdImage> {
+    if let Ok(data) = ob.downcast::<PyBytes>() {
+        return Ok(EpdImage::load_from_memory(data.as_bytes())?);
+    }
+
+    // PIL.Image.Image
+    if ob.hasattr("tobytes")? && ob.hasattr("mode")? {
+        let rgba = ob.call_method1("convert", ("RGBA",))?;
+        let (width, height): (u32, u32) = rgba.getattr("size")?.extract()?;
+        let data: &[u8] = rgba.call_method0("tobytes")?.extract()?;
+
+        return Ok(EpdImage::load_from_raw(
+            width,
+            height,
+            width as usize * 4,
+            PixelLayout::Rgba8,
+            background,
+            data,
+        )?);
+    }
+
+    // QImage
+    if ob.hasattr("convertToFormat")? && ob.hasattr("bytesPerLine")? {
+        // Unlike ARGB32, the byte order of RGBA8888 does not depend on the endianness
+        let rgba = ob.call_method1(
+            "convertToFormat",
+            (ob.getattr("Format")?.getattr("Format_RGBA8888")?,),
+        )?;
+        let width: u32 = rgba.call_method0("width")?.extract()?;
+        let height: u32 = rgba.call_method0("height")?.extract()?;
+        let stride: usize = rgba.call_method0("bytesPerLine")?.extract()?;
+        let bits = rgba.call_method0("constBits")?.call_method0("tobytes")?;
+
+        return Ok(EpdImage::load_from_raw(
+            width,
+            height,
+            stride,
+            PixelLayout::Rgba8,
+            background,
+            bits.extract()?,
+        )?);
+    }
+
+    Err(PyTypeError::new_err(format!(
+        "expected bytes, a PIL image or a QImage, got `{}`",
+        ob.get_type().name()?
+    )))
+}
+
+/// An image loaded with explicit options, accepted wherever an image is taken
+#[pyclass]
+pub struct PyEpdImage(EpdImage);
+
+#[pymethods]
+impl PyEpdImage {
+    /// Loads raw pixel data in the given layout.
+    ///
+    /// `stride` is the number of bytes from the start of one line to the next, the lines are tightly packed if it is not given.
+    /// Transparent pixels are composited onto the gray level `background`
+    #[staticmethod]
+    #[args(stride = "None", background = "0xff")]
+    pub fn from_raw(
+        data: &[u8],
+        width: u32,
+        height: u32,
+        layout: PixelLayout,
+        stride: Option<usize>,
+        background: u8,
+    ) -> PyResult<Self> {
+        let stride = stride.unwrap_or(width as usize * layout.bytes_per_pixel());
+
+        Ok(Self(EpdImage::load_from_raw(
+            width, height, stride, layout, background, data,
+        )?))
+    }
+
+    /// Loads `bytes`, a PIL image or a QImage.
+    /// Transparent pixels of PIL images and QImages are composited onto the gray level `background`
+    #[staticmethod]
+    #[args(background = "0xff")]
+    pub fn from_image(image: &PyAny, background: u8) -> PyResult<Self> {
+        Ok(Self(extract_image(image, background)?))
+    }
+}
+
+/// Renders the image exactly as it would be displayed on the EPD. Returns the preview encoded as PNG.
+///
+/// Pass the capabilities of the connected device to render it for its display.
+#[pyfunction(options = "None", capabilities = "None")]
+pub fn preview_image_from_file(
+    py: Python<'_>,
+    image_file: PathBuf,
+    options: Option<ExportOptions>,
+    capabilities: Option<Capabilities>,
+) -> PyResult<Py<PyBytes>> {
+    preview_image(
+        py,
+        EpdImage::load_from_file(image_file)?,
+        options,
+        capabilities,
+    )
+}
+
+/// Like [preview_image_from_file], but takes the image as `bytes`, a PIL image or a QImage
+#[pyfunction(options = "None", capabilities = "None")]
+pub fn preview_image(
+    py: Python<'_>,
+    image: EpdImage,
+    options: Option<ExportOptions>,
+    capabilities: Option<Capabilities>,
+) -> PyResult<Py<PyBytes>> {
+    let options = options.unwrap_or_default();
+    let format = EpdImageFormat {
+        bit_depth: options.bit_depth,
+        ..capabilities
+            .map(|capabilities| capabilities.epd_format())
+            .unwrap_or_default()
+    };
+    let png = image.preview(&format, &options)?.encode_png()?;
+
+    Ok(PyBytes::new(py, &png).into())
+}
+
+/// The connection, together with the user image as it was last sent to only send the changed regions of the next one
 #[pyclass]
-pub struct PyUsbConnection(UsbConnection);
+pub struct PyUsbConnection(DeviceConnection, Option<PackedImage>);
 
 #[pymethods]
 impl PyUsbConnection {
     #[staticmethod]
     pub fn new() -> PyResult<Self> {
-        Ok(Self(UsbConnection::new()?))
+        Ok(Self(DeviceConnection::Usb(UsbConnection::new()?), None))
+    }
+
+    /// Connects through the given device endpoint, `usb` or `unix:<path>`
+    #[staticmethod]
+    pub fn new_with_device(device: &str) -> PyResult<Self> {
+        let endpoint = device.parse::<DeviceEndpoint>()?;
+        Ok(Self(DeviceConnection::new(&endpoint)?, None))
     }
 
     pub fn handle_events(&mut self) -> PyResult<()> {
-        Ok(self.0.handle_events()?)
+        // A reconnected device may have been replugged, reflashed or replaced and not show the last sent image
+        if self.0.handle_events()? {
+            self.1 = None;
+        }
+        Ok(())
     }
 
     pub fn is_connected(&self) -> bool {
         self.0.is_connected()
     }
 
+    /// The capabilities negotiated with the device when connecting
+    pub fn capabilities(&self) -> Capabilities {
+        self.0.capabilities()
+    }
+
     pub fn retreive_device_status(&self, timeout_ms: u64) -> PyResult<DeviceStatus> {
         Ok(actions::retreive_device_status(
             &self.0,
@@ -54,28 +301,89 @@ impl PyUsbConnection {
         )?)
     }
 
+    #[args(options = "None")]
     pub fn convert_send_user_image_from_file(
-        &self,
+        &mut self,
         image_file: PathBuf,
         timeout_ms: u64,
+        options: Option<ExportOptions>,
     ) -> PyResult<()> {
+        self.1 = None;
         Ok(actions::update_user_image_from_file(
             &self.0,
             image_file,
+            &options.unwrap_or_default(),
             Duration::from_millis(timeout_ms),
         )?)
     }
 
+    #[args(options = "None")]
     pub fn convert_send_app_image_from_file(
         &self,
         app_name: String,
         image_file: PathBuf,
         timeout_ms: u64,
+        options: Option<ExportOptions>,
     ) -> PyResult<()> {
         Ok(actions::update_app_image_from_file(
             &self.0,
             app_name,
             image_file,
+            &options.unwrap_or_default(),
+            Duration::from_millis(timeout_ms),
+        )?)
+    }
+
+    /// Takes the image as `bytes`, a PIL image or a QImage
+    #[args(options = "None")]
+    pub fn convert_send_user_image(
+        &mut self,
+        image: EpdImage,
+        timeout_ms: u64,
+        options: Option<ExportOptions>,
+    ) -> PyResult<()> {
+        self.1 = None;
+        Ok(actions::update_user_image(
+            &self.0,
+            image,
+            &options.unwrap_or_default(),
+            Duration::from_millis(timeout_ms),
+        )?)
+    }
+
+    /// Like `convert_send_user_image`, but only sends the region that changed since the last call.
+    ///
+    /// Returns the sent region, `None` if nothing changed
+    #[args(options = "None")]
+    pub fn convert_send_user_image_region(
+        &mut self,
+        image: EpdImage,
+        timeout_ms: u64,
+        options: Option<ExportOptions>,
+    ) -> PyResult<Option<Region>> {
+        Ok(actions::update_user_image_region(
+            &self.0,
+            image,
+            &mut self.1,
+            &options.unwrap_or_default(),
+            Duration::from_millis(timeout_ms),
+        )?)
+    }
+
+    /// Takes the image as `bytes`, a PIL image or a QImage
+    #[args(options = "None")]
+    pub fn convert_send_app_image(
+        &self,
+        app_name: String,
+        image: EpdImage,
+        timeout_ms: u64,
+        options: Option<ExportOptions>,
+    ) -> PyResult<()> {
+        Ok(actions::update_app_image(
+            &self.0,
+            app_name,
+            image,
+            &options.unwrap_or_default(),
             Duration::from_millis(timeout_ms),
         )?)
     }
@@ -94,4 +402,69 @@ impl PyUsbConnection {
             Duration::from_millis(timeout_ms),
         )?)
     }
+
+    pub fn delete_app_image(&self, app_name: String, timeout_ms: u64) -> PyResult<()> {
+        Ok(actions::delete_app_image(
+            &self.0,
+            app_name,
+            Duration::from_millis(timeout_ms),
+        )?)
+    }
+
+    pub fn rename_app_image(
+        &self,
+        app_name: String,
+        new_app_name: String,
+        timeout_ms: u64,
+    ) -> PyResult<()> {
+        Ok(actions::rename_app_image(
+            &self.0,
+            app_name,
+            new_app_name,
+            Duration::from_millis(timeout_ms),
+        )?)
+    }
+
+    pub fn delete_all_app_images(&self, timeout_ms: u64) -> PyResult<()> {
+        Ok(actions::delete_all_app_images(
+            &self.0,
+            Duration::from_millis(timeout_ms),
+        )?)
+    }
+
+    /// Returns the device settings as JSON
+    pub fn retreive_settings(&self, timeout_ms: u64) -> PyResult<String> {
+        Ok(actions::retreive_settings(&self.0, Duration::from_millis(timeout_ms))?.to_json()?)
+    }
+
+    /// Validates and replaces the device settings, given as JSON
+    pub fn update_settings(&self, settings: &str, timeout_ms: u64) -> PyResult<()> {
+        Ok(actions::update_settings(
+            &self.0,
+            &DeviceSettings::from_json(settings)?,
+            Duration::from_millis(timeout_ms),
+        )?)
+    }
+
+    /// Returns the user image stored on the device, encoded as PNG
+    pub fn retreive_user_image(&self, py: Python<'_>, timeout_ms: u64) -> PyResult<Py<PyBytes>> {
+        let png = actions::retreive_user_image(&self.0, Duration::from_millis(timeout_ms))?
+            .encode_png()?;
+
+        Ok(PyBytes::new(py, &png).into())
+    }
+
+    /// Returns the image stored on the device for the app, encoded as PNG
+    pub fn retreive_app_image(
+        &self,
+        py: Python<'_>,
+        app_name: String,
+        timeout_ms: u64,
+    ) -> PyResult<Py<PyBytes>> {
+        let png =
+            actions::retreive_app_image(&self.0, app_name, Duration::from_millis(timeout_ms))?
+                .encode_png()?;
+
+        Ok(PyBytes::new(py, &png).into())
+    }
 }
@@ -0,0 +1,349 @@
+use pyo3::prelude::*;
+
+use crate::{DriverError, EpdImageFormat};
+
+/// The controller addresses the display RAM in whole bytes,
+/// so regions start and end on a multiple of 8 px horizontally
+pub const REGION_ALIGNMENT: u32 = 8;
+
+/// A rectangle of the display, in px
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[pyclass]
+pub struct Region {
+    #[pyo3(get)]
+    pub x: u32,
+    #[pyo3(get)]
+    pub y: u32,
+    #[pyo3(get)]
+    pub width: u32,
+    #[pyo3(get)]
+    pub height: u32,
+}
+
+impl Region {
+    /// The region covering the whole display
+    pub fn full(format: &EpdImageFormat) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: format.width,
+            height: format.height,
+        }
+    }
+
+    /// Widens the region horizontally so that it starts and ends on a multiple of `alignment`,
+    /// without exceeding the width of the display
+    pub fn align(self, alignment: u32, display_width: u32) -> Self {
+        let x = self.x / alignment * alignment;
+        let end = ((self.x + self.width).div_ceil(alignment) * alignment).min(display_width);
+
+        Self {
+            x,
+            width: end - x,
+            ..self
+        }
+    }
+
+    /// The format of the image data of the region, cropped from an image in `format`
+    pub fn format(&self, format: &EpdImageFormat) -> EpdImageFormat {
+        EpdImageFormat {
+            width: self.width,
+            height: self.height,
+            ..*format
+        }
+    }
+}
+
+/// Image data packed in its format, as produced by [crate::EpdImage::export]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedImage {
+    pub format: EpdImageFormat,
+    pub data: Vec<u8>,
+}
+
+/// The smallest region that contains all pixels which differ between the two images, `None` if they are equal.
+pub fn dirty_region(
+    format: &EpdImageFormat,
+    previous: &[u8],
+    current: &[u8],
+) -> Result<Option<Region>, DriverError> {
+    check_len(format, previous)?;
+    check_len(format, current)?;
+
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+
+    for plane in 0..format.planes() {
+        for y in 0..format.height {
+            for x in 0..format.width {
+                let bit = px_bit(format, plane, x, y);
+                let bits_per_pixel = format.bit_depth.bits_per_pixel();
+                if get_px(previous, bit, bits_per_pixel) == get_px(current, bit, bits_per_pixel) {
+                    continue;
+                }
+
+                bounds = Some(match bounds {
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                    None => (x, y, x, y),
+                });
+            }
+        }
+    }
+
+    Ok(bounds.map(|(x0, y0, x1, y1)| Region {
+        x: x0,
+        y: y0,
+        width: x1 - x0 + 1,
+        height: y1 - y0 + 1,
+    }))
+}
+
+/// Copies the pixels of the region out of the packed image data, packed in [Region::format]
+pub fn crop_packed(
+    format: &EpdImageFormat,
+    data: &[u8],
+    region: Region,
+) -> Result<Vec<u8>, DriverError> {
+    check_len(format, data)?;
+    check_bounds(format, region)?;
+
+    let region_format = region.format(format);
+    let bits_per_pixel = format.bit_depth.bits_per_pixel();
+    let mut region_data = vec![0x00; region_format.packed_len()];
+
+    for plane in 0..format.planes() {
+        for y in 0..region.height {
+            for x in 0..region.width {
+                let value = get_px(
+                    data,
+                    px_bit(format, plane, region.x + x, region.y + y),
+                    bits_per_pixel,
+                );
+                set_px(
+                    &mut region_data,
+                    px_bit(&region_format, plane, x, y),
+                    bits_per_pixel,
+                    value,
+                );
+            }
+        }
+    }
+
+    Ok(region_data)
+}
+
+/// Inverts [crop_packed], copying the pixels of the region into the packed image data
+pub fn paste_packed(
+    format: &EpdImageFormat,
+    data: &mut [u8],
+    region: Region,
+    region_data: &[u8],
+) -> Result<(), DriverError> {
+    let region_format = region.format(format);
+    check_len(format, data)?;
+    check_len(&region_format, region_data)?;
+    check_bounds(format, region)?;
+
+    let bits_per_pixel = format.bit_depth.bits_per_pixel();
+
+    for plane in 0..format.planes() {
+        for y in 0..region.height {
+            for x in 0..region.width {
+                let value = get_px(
+                    region_data,
+                    px_bit(&region_format, plane, x, y),
+                    bits_per_pixel,
+                );
+                set_px(
+                    data,
+                    px_bit(format, plane, region.x + x, region.y + y),
+                    bits_per_pixel,
+                    value,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_len(format: &EpdImageFormat, data: &[u8]) -> Result<(), DriverError> {
+    if data.len() < format.packed_len() {
+        return Err(DriverError::ImageDataSize {
+            width: format.width,
+            height: format.height,
+            len: data.len(),
+        });
+    }
+
+    Ok(())
+}
+
+fn check_bounds(format: &EpdImageFormat, region: Region) -> Result<(), DriverError> {
+    if region.x + region.width > format.width || region.y + region.height > format.height {
+        return Err(DriverError::InvalidRegion {
+            x: region.x,
+            y: region.y,
+            width: region.width,
+            height: region.height,
+        });
+    }
+
+    Ok(())
+}
+
+/// The offset in bits of the pixel in the packed image data
+fn px_bit(format: &EpdImageFormat, plane: usize, x: u32, y: u32) -> usize {
+    plane * format.plane_len() * 8
+        + (y as usize * format.width as usize + x as usize) * format.bit_depth.bits_per_pixel()
+}
+
+/// Pixels never straddle two bytes, as the bits per pixel divide 8
+fn get_px(data: &[u8], bit: usize, bits_per_pixel: usize) -> u8 {
+    let mask = (1 << bits_per_pixel) - 1;
+    let shift = 8 - bits_per_pixel - bit % 8;
+
+    (data[bit / 8] >> shift) & mask
+}
+
+fn set_px(data: &mut [u8], bit: usize, bits_per_pixel: usize, value: u8) {
+    let mask = (1 << bits_per_pixel) - 1;
+    let shift = 8 - bits_per_pixel - bit % 8;
+
+    data[bit / 8] = data[bit / 8] & !(mask << shift) | (value & mask) << shift;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crop_packed, dirty_region, paste_packed, px_bit, set_px, Region};
+    use crate::{BitDepth, DisplayColors, EpdImageFormat};
+
+    fn formats() -> [EpdImageFormat; 3] {
+        [
+            EpdImageFormat {
+                width: 20,
+                height: 5,
+                ..Default::default()
+            },
+            EpdImageFormat {
+                width: 20,
+                height: 5,
+                colors: DisplayColors::BlackWhiteRed,
+                ..Default::default()
+            },
+            EpdImageFormat {
+                width: 20,
+                height: 5,
+                bit_depth: BitDepth::Bpp2,
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn dirty_region_bounds_changes() {
+        for format in formats() {
+            let previous = vec![0xff; format.packed_len()];
+            assert_eq!(dirty_region(&format, &previous, &previous).unwrap(), None);
+
+            // clear pixel (3, 1) in the first and pixel (9, 3) in the last plane
+            let mut current = previous.clone();
+            let bits_per_pixel = format.bit_depth.bits_per_pixel();
+            set_px(&mut current, px_bit(&format, 0, 3, 1), bits_per_pixel, 0);
+            let last_plane = format.planes() - 1;
+            set_px(
+                &mut current,
+                px_bit(&format, last_plane, 9, 3),
+                bits_per_pixel,
+                0,
+            );
+
+            assert_eq!(
+                dirty_region(&format, &previous, &current).unwrap(),
+                Some(Region {
+                    x: 3,
+                    y: 1,
+                    width: 7,
+                    height: 3
+                }),
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn crop_paste_roundtrip() {
+        for format in formats() {
+            let data = (0..format.packed_len())
+                .map(|i| (i * 37) as u8)
+                .collect::<Vec<u8>>();
+            let region = Region {
+                x: 3,
+                y: 1,
+                width: 11,
+                height: 3,
+            };
+
+            let region_data = crop_packed(&format, &data, region).unwrap();
+            assert_eq!(region_data.len(), region.format(&format).packed_len());
+
+            let mut pasted = vec![0x00; format.packed_len()];
+            paste_packed(&format, &mut pasted, region, &region_data).unwrap();
+            assert_eq!(
+                crop_packed(&format, &pasted, region).unwrap(),
+                region_data,
+                "{format:?}"
+            );
+
+            // the pixels outside of the region are untouched
+            let outside = Region {
+                x: 14,
+                y: 0,
+                width: 6,
+                height: 5,
+            };
+            assert!(crop_packed(&format, &pasted, outside)
+                .unwrap()
+                .iter()
+                .all(|&byte| byte == 0x00));
+        }
+    }
+
+    #[test]
+    fn align() {
+        let region = Region {
+            x: 3,
+            y: 1,
+            width: 6,
+            height: 2,
+        };
+        assert_eq!(
+            region.align(8, 20),
+            Region {
+                x: 0,
+                y: 1,
+                width: 16,
+                height: 2
+            }
+        );
+
+        let region = Region {
+            x: 17,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        assert_eq!(region.align(8, 20).x + region.align(8, 20).width, 20);
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let format = formats()[0];
+        let region = Region {
+            x: 15,
+            y: 0,
+            width: 6,
+            height: 1,
+        };
+        assert!(crop_packed(&format, &vec![0x00; format.packed_len()], region).is_err());
+    }
+}
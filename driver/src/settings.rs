@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::DriverError;
+
+/// The settings the firmware stores in a JSON file on the mSD.
+///
+/// Which settings exist is up to the firmware, so they are kept exactly as they are stored in the file.
+/// Settings are neither added nor removed by the host, only changed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DeviceSettings {
+    pub values: Map<String, Value>,
+}
+
+impl DeviceSettings {
+    /// The maximum length of the JSON, as the length of the settings string is sent as `u16`
+    pub const JSON_LEN_MAX: usize = u16::MAX as usize;
+
+    /// Parses the settings without validating them,
+    /// so that settings the firmware stored with invalid values can still be read and fixed
+    pub fn from_json(json: &str) -> Result<Self, DriverError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_json(&self) -> Result<String, DriverError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Checks that the settings can be sent to the firmware
+    pub fn validate(&self) -> Result<(), DriverError> {
+        if self.to_json()?.len() > Self::JSON_LEN_MAX {
+            return Err(DriverError::InvalidSetting(format!(
+                "the settings JSON must not be longer than {} bytes",
+                Self::JSON_LEN_MAX
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value of a single setting
+    pub fn get(&self, key: &str) -> Result<Value, DriverError> {
+        self.values
+            .get(key)
+            .cloned()
+            .ok_or_else(|| DriverError::InvalidSetting(format!("unknown setting `{key}`")))
+    }
+
+    /// Changes a single setting, which must already exist in the settings of the device.
+    ///
+    /// The value is parsed as JSON, if that fails it is taken as a string.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), DriverError> {
+        let mut changed = self.clone();
+
+        let setting = changed
+            .values
+            .get_mut(key)
+            .ok_or_else(|| DriverError::InvalidSetting(format!("unknown setting `{key}`")))?;
+        *setting = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+        changed.validate()?;
+
+        *self = changed;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceSettings;
+
+    #[test]
+    fn json_roundtrip_keeps_settings() {
+        let json = r#"{ "startup_page": "user-image", "led_brightness": 20 }"#;
+        let settings = DeviceSettings::from_json(json).unwrap();
+
+        assert_eq!(settings.get("startup_page").unwrap(), "user-image");
+        assert_eq!(settings.get("led_brightness").unwrap(), 20);
+        // Missing settings stay missing
+        assert_eq!(settings.values.len(), 2);
+
+        let roundtrip = DeviceSettings::from_json(&settings.to_json().unwrap()).unwrap();
+        assert_eq!(roundtrip, settings);
+    }
+
+    #[test]
+    fn set_validates() {
+        let mut settings =
+            DeviceSettings::from_json(r#"{ "led_brightness": 20, "name": "desk" }"#).unwrap();
+
+        settings.set("led_brightness", "5").unwrap();
+        settings.set("name", "office").unwrap();
+        assert_eq!(settings.get("led_brightness").unwrap(), 5);
+        assert_eq!(settings.get("name").unwrap(), "office");
+
+        assert!(settings.set("does_not_exist", "1").is_err());
+        assert!(settings
+            .set("name", &"x".repeat(DeviceSettings::JSON_LEN_MAX))
+            .is_err());
+        // failed changes leave the settings untouched
+        assert_eq!(settings.values.len(), 2);
+        assert_eq!(settings.get("name").unwrap(), "office");
+    }
+}
@@ -0,0 +1,644 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::capabilities::{self, Features};
+use crate::connection::{USB_DEVICE_MSG_LEN, USB_HOST_MSG_LEN};
+use crate::{
+    compression, region, Capabilities, DeviceMessage, DeviceSettings, DeviceStatus, DisplayColors,
+    DriverError, Encoding, EpdImageFormat, EpdPage, HostMessage, PackedImage, Region,
+    TransferStatus, Transport, EPD_HEIGHT, EPD_WIDTH, PROTOCOL_VERSION,
+};
+
+/// The firmware version reported by the simulator
+pub const SIMULATOR_FIRMWARE_VERSION: (u8, u8, u8) = (0, 1, 0);
+/// The highest host message variant understood by firmware that predates the handshake
+const LEGACY_HOST_MSG_VARIANT_MAX: u8 = 0x08;
+/// The first protocol version in which the host sends the CRC32 of its transfers
+const CHECKSUM_PROTOCOL_VERSION: u8 = 2;
+/// The first protocol version in which the host understands DataLength messages
+const TRANSFER_LENGTH_PROTOCOL_VERSION: u8 = 3;
+/// The size of the simulated mSD in KiB
+pub const SIMULATOR_STORAGE_KIB: u32 = 1024 * 1024;
+
+/// The transfer the device is currently receiving data messages for
+#[derive(Debug, Clone)]
+enum Transfer {
+    Idle,
+    UserImage {
+        format: EpdImageFormat,
+        encoding: Encoding,
+        data: Vec<u8>,
+    },
+    Region {
+        region: Region,
+        format: EpdImageFormat,
+        encoding: Encoding,
+        data: Vec<u8>,
+    },
+    AppImageName {
+        format: EpdImageFormat,
+        encoding: Encoding,
+        str_len: u16,
+        data: Vec<u8>,
+    },
+    AppImageData {
+        format: EpdImageFormat,
+        encoding: Encoding,
+        app_name: String,
+        data: Vec<u8>,
+    },
+    ActiveApp {
+        str_len: u16,
+        data: Vec<u8>,
+    },
+    RequestedAppImageName {
+        str_len: u16,
+        data: Vec<u8>,
+    },
+    DeletedAppImageName {
+        str_len: u16,
+        data: Vec<u8>,
+    },
+    RenamedAppImageName {
+        str_len: u16,
+        new_str_len: u16,
+        data: Vec<u8>,
+    },
+    RenamedAppImageNewName {
+        app_name: String,
+        str_len: u16,
+        data: Vec<u8>,
+    },
+    Settings {
+        str_len: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl Transfer {
+    /// The payloads of the data messages received so far
+    fn data_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            Transfer::Idle => None,
+            Transfer::UserImage { data, .. }
+            | Transfer::Region { data, .. }
+            | Transfer::AppImageName { data, .. }
+            | Transfer::AppImageData { data, .. }
+            | Transfer::ActiveApp { data, .. }
+            | Transfer::RequestedAppImageName { data, .. }
+            | Transfer::DeletedAppImageName { data, .. }
+            | Transfer::RenamedAppImageName { data, .. }
+            | Transfer::RenamedAppImageNewName { data, .. }
+            | Transfer::Settings { data, .. } => Some(data),
+        }
+    }
+}
+
+/// A software model of the deskassistant firmware.
+///
+/// It consumes host message frames and produces the device message frames the firmware would answer with.
+#[derive(Debug, Clone)]
+pub struct DeviceSimulator {
+    /// Behave like firmware that predates the handshake
+    legacy: bool,
+    /// The protocol version the host announced in the handshake, 0 if there was none
+    host_protocol_version: u8,
+    /// The length of the data of the current transfer declared by the host
+    transfer_len: Option<usize>,
+    display_width: u16,
+    display_height: u16,
+    display_colors: DisplayColors,
+    current_epd_page: EpdPage,
+    user_image: Option<PackedImage>,
+    app_images: BTreeMap<String, PackedImage>,
+    active_app: Option<String>,
+    /// The content of the settings file
+    settings: String,
+    display_refresh_cnt: usize,
+    started: Instant,
+    transfer: Transfer,
+    device_frames: VecDeque<[u8; USB_DEVICE_MSG_LEN]>,
+}
+
+impl Default for DeviceSimulator {
+    fn default() -> Self {
+        Self {
+            legacy: false,
+            host_protocol_version: 0,
+            transfer_len: None,
+            display_width: EPD_WIDTH as u16,
+            display_height: EPD_HEIGHT as u16,
+            display_colors: DisplayColors::BlackWhite,
+            current_epd_page: EpdPage::Overview,
+            user_image: None,
+            app_images: BTreeMap::new(),
+            active_app: None,
+            settings: DeviceSettings::default().to_json().unwrap(),
+            display_refresh_cnt: 0,
+            started: Instant::now(),
+            transfer: Transfer::Idle,
+            device_frames: VecDeque::new(),
+        }
+    }
+}
+
+impl DeviceSimulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A simulator of firmware that predates the handshake.
+    /// It only understands the original messages and reports the short form of the device status
+    pub fn legacy() -> Self {
+        Self {
+            legacy: true,
+            ..Self::default()
+        }
+    }
+
+    /// Simulates a device with a different display, which is reported in the handshake and the device status
+    pub fn with_display(self, width: u16, height: u16, colors: DisplayColors) -> Self {
+        Self {
+            display_width: width,
+            display_height: height,
+            display_colors: colors,
+            ..self
+        }
+    }
+
+    /// Simulates a device whose settings file has the given content
+    pub fn with_settings(self, settings: String) -> Self {
+        Self { settings, ..self }
+    }
+
+    pub fn current_epd_page(&self) -> EpdPage {
+        self.current_epd_page
+    }
+
+    pub fn user_image(&self) -> Option<&PackedImage> {
+        self.user_image.as_ref()
+    }
+
+    pub fn app_images(&self) -> &BTreeMap<String, PackedImage> {
+        &self.app_images
+    }
+
+    pub fn active_app(&self) -> Option<&str> {
+        self.active_app.as_deref()
+    }
+
+    pub fn settings(&self) -> &str {
+        &self.settings
+    }
+
+    pub fn display_refresh_cnt(&self) -> usize {
+        self.display_refresh_cnt
+    }
+
+    /// Processes a single frame sent by the host, queueing up the answering device frames.
+    pub fn handle_host_frame(&mut self, frame: &[u8; USB_HOST_MSG_LEN]) -> Result<(), DriverError> {
+        if self.legacy && frame[0] > LEGACY_HOST_MSG_VARIANT_MAX {
+            return Err(DriverError::InvalidMessageVariant(frame[0]));
+        }
+
+        let host_message = HostMessage::from_data(frame)?;
+        log::debug!("simulator received host message: `{host_message:?}`");
+
+        match (&mut self.transfer, host_message) {
+            (
+                Transfer::Idle,
+                host_message @ (HostMessage::Data { .. }
+                | HostMessage::DataComplete { .. }
+                | HostMessage::DataLength { .. }),
+            ) => {
+                return Err(DriverError::UnexpectedMessage {
+                    expected: "a transfer starting message",
+                    actual: host_message.variant_name(),
+                });
+            }
+            (
+                Transfer::UserImage { data, .. }
+                | Transfer::Region { data, .. }
+                | Transfer::AppImageName { data, .. }
+                | Transfer::AppImageData { data, .. }
+                | Transfer::ActiveApp { data, .. }
+                | Transfer::RequestedAppImageName { data, .. }
+                | Transfer::DeletedAppImageName { data, .. }
+                | Transfer::RenamedAppImageName { data, .. }
+                | Transfer::RenamedAppImageNewName { data, .. }
+                | Transfer::Settings { data, .. },
+                HostMessage::Data { data: chunk },
+            ) => {
+                data.extend_from_slice(&chunk);
+            }
+            (_, HostMessage::DataLength { len }) => self.transfer_len = Some(len as usize),
+            (_, HostMessage::DataComplete { crc32 }) => self.complete_transfer(crc32)?,
+            (Transfer::Idle, HostMessage::RequestDeviceStatus) => {
+                let status = if self.legacy {
+                    DeviceStatus::short(self.current_epd_page)
+                } else {
+                    self.device_status()
+                };
+                self.queue_device_message(DeviceMessage::DeviceStatus(status));
+            }
+            (Transfer::Idle, HostMessage::RefreshDisplay) => {
+                self.display_refresh_cnt += 1;
+            }
+            (Transfer::Idle, HostMessage::SwitchPage(page)) => {
+                self.current_epd_page = page;
+            }
+            (Transfer::Idle, HostMessage::UpdateUserImage { format, encoding }) => {
+                self.transfer = Transfer::UserImage {
+                    format,
+                    encoding,
+                    data: vec![],
+                };
+            }
+            (
+                Transfer::Idle,
+                HostMessage::UpdateRegion {
+                    x,
+                    y,
+                    format,
+                    encoding,
+                },
+            ) => {
+                self.transfer = Transfer::Region {
+                    region: Region {
+                        x: x as u32,
+                        y: y as u32,
+                        width: format.width,
+                        height: format.height,
+                    },
+                    format,
+                    encoding,
+                    data: vec![],
+                };
+            }
+            (
+                Transfer::Idle,
+                HostMessage::UpdateAppImage {
+                    app_name_str_len,
+                    format,
+                    encoding,
+                },
+            ) => {
+                self.transfer = Transfer::AppImageName {
+                    format,
+                    encoding,
+                    str_len: app_name_str_len,
+                    data: vec![],
+                };
+            }
+            (Transfer::Idle, HostMessage::ReportActiveApp { str_len }) => {
+                self.transfer = Transfer::ActiveApp {
+                    str_len,
+                    data: vec![],
+                };
+            }
+            (Transfer::Idle, HostMessage::RequestListAppImages) => {
+                let list_str = self
+                    .app_images
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                let str_len = list_str.len() as u16;
+
+                let mut list_data = list_str.into_bytes();
+                list_data.push(0x00);
+
+                self.queue_device_message(DeviceMessage::ListAppImages { str_len });
+                self.queue_device_data(&list_data);
+            }
+            (Transfer::Idle, HostMessage::RequestUserImage) => {
+                self.queue_stored_image(self.user_image.clone());
+            }
+            (Transfer::Idle, HostMessage::RequestAppImage { app_name_str_len }) => {
+                self.transfer = Transfer::RequestedAppImageName {
+                    str_len: app_name_str_len,
+                    data: vec![],
+                };
+            }
+            (Transfer::Idle, HostMessage::DeleteAppImage { app_name_str_len }) => {
+                self.transfer = Transfer::DeletedAppImageName {
+                    str_len: app_name_str_len,
+                    data: vec![],
+                };
+            }
+            (
+                Transfer::Idle,
+                HostMessage::RenameAppImage {
+                    app_name_str_len,
+                    new_app_name_str_len,
+                },
+            ) => {
+                self.transfer = Transfer::RenamedAppImageName {
+                    str_len: app_name_str_len,
+                    new_str_len: new_app_name_str_len,
+                    data: vec![],
+                };
+            }
+            (Transfer::Idle, HostMessage::DeleteAllAppImages) => {
+                self.app_images.clear();
+            }
+            (Transfer::Idle, HostMessage::Hello { protocol_version }) => {
+                self.host_protocol_version = protocol_version;
+                self.queue_device_message(DeviceMessage::Capabilities {
+                    protocol_version: PROTOCOL_VERSION,
+                    firmware_version: SIMULATOR_FIRMWARE_VERSION,
+                    features: Features::ALL,
+                    display_width: self.display_width,
+                    display_height: self.display_height,
+                    display_colors: self.display_colors,
+                });
+            }
+            (Transfer::Idle, HostMessage::RequestSettings) => {
+                let str_len = self.settings.len() as u16;
+
+                let mut settings_data = self.settings.clone().into_bytes();
+                settings_data.push(0x00);
+
+                self.queue_device_message(DeviceMessage::Settings { str_len });
+                self.queue_device_data(&settings_data);
+            }
+            (Transfer::Idle, HostMessage::UpdateSettings { str_len }) => {
+                self.transfer = Transfer::Settings {
+                    str_len,
+                    data: vec![],
+                };
+            }
+            (_, msg) => {
+                return Err(DriverError::UnexpectedMessage {
+                    expected: "Data",
+                    actual: msg.variant_name(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes the next frame the device sends to the host, if there is one.
+    pub fn next_device_frame(&mut self) -> Option<[u8; USB_DEVICE_MSG_LEN]> {
+        self.device_frames.pop_front()
+    }
+
+    fn complete_transfer(&mut self, crc32: u32) -> Result<(), DriverError> {
+        let mut transfer = std::mem::replace(&mut self.transfer, Transfer::Idle);
+        let transfer_len = self.transfer_len.take();
+
+        // Drop the padding of the last data message
+        if let (Some(len), Some(data)) = (transfer_len, transfer.data_mut()) {
+            data.truncate(len);
+        }
+
+        // Hosts that predate checksums neither send them nor wait for the acknowledgement
+        if self.host_supports(CHECKSUM_PROTOCOL_VERSION) {
+            let received = transfer
+                .data_mut()
+                .map(|data| &data[..])
+                .unwrap_or_default();
+            if crc32fast::hash(received) != crc32 {
+                log::warn!("simulator received a transfer with a checksum mismatch, discarding it");
+                self.queue_device_message(DeviceMessage::TransferAck(
+                    TransferStatus::ChecksumMismatch,
+                ));
+                return Ok(());
+            }
+            self.queue_device_message(DeviceMessage::TransferAck(TransferStatus::Ok));
+        }
+
+        match transfer {
+            Transfer::Idle => {}
+            Transfer::UserImage {
+                format,
+                encoding,
+                data,
+            } => {
+                let data = compression::decode(encoding, &data, format.packed_len())?;
+                self.user_image = Some(PackedImage { format, data });
+            }
+            Transfer::Region {
+                region,
+                format,
+                encoding,
+                data,
+            } => {
+                let data = compression::decode(encoding, &data, format.packed_len())?;
+                match self.user_image.as_mut() {
+                    Some(user_image)
+                        if user_image.format.colors == format.colors
+                            && user_image.format.bit_depth == format.bit_depth =>
+                    {
+                        let user_format = user_image.format;
+                        region::paste_packed(&user_format, &mut user_image.data, region, &data)?;
+                    }
+                    _ => log::warn!("region does not match the stored user image, ignoring it"),
+                }
+            }
+            Transfer::AppImageName {
+                format,
+                encoding,
+                str_len,
+                data,
+            } => {
+                self.transfer = Transfer::AppImageData {
+                    format,
+                    encoding,
+                    app_name: extract_str(data, str_len)?,
+                    data: vec![],
+                };
+            }
+            Transfer::AppImageData {
+                format,
+                encoding,
+                app_name,
+                data,
+            } => {
+                let data = compression::decode(encoding, &data, format.packed_len())?;
+                self.app_images
+                    .insert(app_name, PackedImage { format, data });
+            }
+            Transfer::ActiveApp { str_len, data } => {
+                self.active_app = Some(extract_str(data, str_len)?);
+            }
+            Transfer::RequestedAppImageName { str_len, data } => {
+                let app_name = extract_str(data, str_len)?;
+                self.queue_stored_image(self.app_images.get(&app_name).cloned());
+            }
+            Transfer::DeletedAppImageName { str_len, data } => {
+                self.app_images.remove(&extract_str(data, str_len)?);
+            }
+            Transfer::RenamedAppImageName {
+                str_len,
+                new_str_len,
+                data,
+            } => {
+                self.transfer = Transfer::RenamedAppImageNewName {
+                    app_name: extract_str(data, str_len)?,
+                    str_len: new_str_len,
+                    data: vec![],
+                };
+            }
+            Transfer::RenamedAppImageNewName {
+                app_name,
+                str_len,
+                data,
+            } => {
+                let new_app_name = extract_str(data, str_len)?;
+                // Renaming a missing image does nothing, an existing image with the new name is replaced
+                if let Some(image) = self.app_images.remove(&app_name) {
+                    self.app_images.insert(new_app_name, image);
+                }
+            }
+            Transfer::Settings { str_len, data } => {
+                self.settings = extract_str(data, str_len)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn device_status(&self) -> DeviceStatus {
+        let used_bytes = self
+            .app_images
+            .values()
+            .chain(self.user_image.as_ref())
+            .map(|image| image.data.len())
+            .sum::<usize>()
+            + self.settings.len();
+
+        DeviceStatus {
+            current_epd_page: self.current_epd_page,
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: Some(SIMULATOR_FIRMWARE_VERSION),
+            display_width: Some(self.display_width),
+            display_height: Some(self.display_height),
+            storage_free_kib: Some(
+                SIMULATOR_STORAGE_KIB.saturating_sub(used_bytes.div_ceil(1024) as u32),
+            ),
+            storage_total_kib: Some(SIMULATOR_STORAGE_KIB),
+            app_image_cnt: Some(self.app_images.len() as u16),
+            displayed_app: self
+                .active_app
+                .clone()
+                .filter(|app| self.app_images.contains_key(app)),
+            uptime_s: Some(self.started.elapsed().as_secs() as u32),
+        }
+    }
+
+    fn queue_device_message(&mut self, msg: DeviceMessage) {
+        self.device_frames.push_back(msg.into_data());
+    }
+
+    /// Queues the answer to an image request
+    fn queue_stored_image(&mut self, image: Option<PackedImage>) {
+        match image {
+            Some(PackedImage { format, data }) => {
+                self.queue_device_message(DeviceMessage::StoredImage { format });
+                self.queue_device_data(&data);
+            }
+            None => self.queue_device_message(DeviceMessage::ImageNotFound),
+        }
+    }
+
+    /// Whether the host announced the protocol version in the handshake
+    fn host_supports(&self, protocol_version: u8) -> bool {
+        !self.legacy && self.host_protocol_version >= protocol_version
+    }
+
+    /// Queues the data in data messages, followed by a DataComplete message.
+    /// The length is declared up front to hosts that understand it
+    fn queue_device_data(&mut self, data: &[u8]) {
+        if self.host_supports(TRANSFER_LENGTH_PROTOCOL_VERSION) {
+            self.queue_device_message(DeviceMessage::DataLength {
+                len: data.len() as u32,
+            });
+        }
+        for chunk in data.chunks(USB_DEVICE_MSG_LEN - 1) {
+            let mut chunk = chunk.to_vec();
+            chunk.resize(USB_DEVICE_MSG_LEN - 1, 0x00);
+
+            self.queue_device_message(DeviceMessage::Data {
+                data: chunk.try_into().unwrap(),
+            });
+        }
+        self.queue_device_message(DeviceMessage::DataComplete);
+    }
+}
+
+fn extract_str(mut data: Vec<u8>, str_len: u16) -> Result<String, DriverError> {
+    if data.len() < str_len as usize {
+        return Err(DriverError::TransferTooShort {
+            expected: str_len as usize,
+            len: data.len(),
+        });
+    }
+    data.truncate(str_len as usize);
+
+    Ok(String::from_utf8(data)?)
+}
+
+/// A connection to an in-process [DeviceSimulator].
+///
+/// Reading a device message when the simulator has nothing to send fails immediately
+/// with the timeout error a real device would produce.
+#[derive(Debug)]
+pub struct SimulatorConnection {
+    device: Mutex<DeviceSimulator>,
+    capabilities: Capabilities,
+}
+
+impl Default for SimulatorConnection {
+    fn default() -> Self {
+        Self::new(DeviceSimulator::default())
+    }
+}
+
+impl SimulatorConnection {
+    /// Connects to the simulator, performing the handshake
+    pub fn new(device: DeviceSimulator) -> Self {
+        let mut connection = Self {
+            device: Mutex::new(device),
+            capabilities: Capabilities::legacy(),
+        };
+        // The legacy simulator rejects the Hello message, failing the handshake
+        connection.capabilities = capabilities::handshake(&connection, Duration::ZERO)
+            .unwrap_or_else(|e| {
+                log::warn!("handshake failed with Err {e}, assuming legacy firmware");
+                Capabilities::legacy()
+            });
+
+        connection
+    }
+
+    /// Gives access to the simulated device, for inspecting or modifying its state.
+    pub fn device(&self) -> MutexGuard<'_, DeviceSimulator> {
+        self.device.lock().unwrap()
+    }
+}
+
+impl Transport for SimulatorConnection {
+    fn send_host_message(&self, msg: HostMessage, _timeout: Duration) -> Result<(), DriverError> {
+        self.device().handle_host_frame(&msg.into_data())
+    }
+
+    fn read_device_message(&self, _timeout: Duration) -> Result<DeviceMessage, DriverError> {
+        let data = self
+            .device()
+            .next_device_frame()
+            .ok_or(DriverError::UsbTimeout)?;
+
+        let device_message = DeviceMessage::from_data(&data)?;
+
+        log::debug!("received device message: `{device_message:?}`");
+
+        Ok(device_message)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+}
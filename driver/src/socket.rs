@@ -0,0 +1,178 @@
+use std::cell::Cell;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::capabilities::LazyCapabilities;
+use crate::connection::{USB_DEVICE_MSG_LEN, USB_HOST_MSG_LEN};
+use crate::simulator::DeviceSimulator;
+use crate::{Capabilities, DeviceMessage, DriverError, HostMessage, Transport};
+
+/// A connection to a stand-in device listening on a unix socket.
+///
+/// It exchanges the same 64 byte frames that the usb connection sends over its bulk endpoints.
+pub struct SocketConnection {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+    /// Set when sending or reading found the other end closed, the stream is dropped in [Self::handle_events]
+    closed: Cell<bool>,
+    capabilities: LazyCapabilities,
+}
+
+impl SocketConnection {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            stream: None,
+            closed: Cell::new(false),
+            capabilities: LazyCapabilities::default(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some() && !self.closed.get()
+    }
+
+    /// Drops the stream if the other end was found closed and connects to the socket if not connected.
+    /// As for the usb connection the handshake is done before the first message, not here.
+    ///
+    /// Returns whether it disconnected or connected.
+    pub fn handle_events(&mut self) -> Result<bool, DriverError> {
+        let mut changed = false;
+
+        if self.stream.is_some() && self.closed.get() {
+            log::info!("socket `{}` was closed", self.path.display());
+
+            self.stream = None;
+            self.capabilities.reset();
+            changed = true;
+        }
+
+        if self.stream.is_none() {
+            match UnixStream::connect(&self.path) {
+                Ok(stream) => {
+                    self.stream.replace(stream);
+                    self.closed.set(false);
+                    self.capabilities.reset();
+                    changed = true;
+                }
+                Err(e) => {
+                    log::debug!(
+                        "connecting to socket `{}` failed with Err {e}",
+                        self.path.display()
+                    );
+                }
+            }
+        }
+
+        Ok(changed)
+    }
+
+    fn stream(&self) -> Result<&UnixStream, DriverError> {
+        self.stream
+            .as_ref()
+            .filter(|_| !self.closed.get())
+            .ok_or(DriverError::NotConnected)
+    }
+
+    /// Notes when the error shows that the other end closed the connection
+    fn check_closed<T>(&self, result: std::io::Result<T>) -> Result<T, DriverError> {
+        if let Err(e) = &result {
+            if matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+            ) {
+                self.closed.set(true);
+            }
+        }
+
+        Ok(result?)
+    }
+}
+
+impl Transport for SocketConnection {
+    fn send_host_message(&self, msg: HostMessage, timeout: Duration) -> Result<(), DriverError> {
+        let data = msg.into_data();
+
+        let mut stream = self.stream()?;
+        self.capabilities.negotiate(self);
+
+        stream.set_write_timeout(socket_timeout(timeout))?;
+        self.check_closed(stream.write_all(&data))?;
+        Ok(())
+    }
+
+    fn read_device_message(&self, timeout: Duration) -> Result<DeviceMessage, DriverError> {
+        let mut data = [0_u8; USB_DEVICE_MSG_LEN];
+
+        let mut stream = self.stream()?;
+        self.capabilities.negotiate(self);
+
+        stream.set_read_timeout(socket_timeout(timeout))?;
+        self.check_closed(stream.read_exact(&mut data))?;
+
+        let device_message = DeviceMessage::from_data(&data)?;
+
+        log::debug!("received device message: `{device_message:?}`");
+
+        Ok(device_message)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        if !self.is_connected() {
+            return Capabilities::legacy();
+        }
+
+        self.capabilities.negotiate(self)
+    }
+}
+
+/// A zero timeout means no timeout, as for the usb connection. The socket rejects zero durations
+fn socket_timeout(timeout: Duration) -> Option<Duration> {
+    (!timeout.is_zero()).then_some(timeout)
+}
+
+/// Serves the simulated device on the listener, handling one connection after another.
+/// Blocks forever, unless accepting a connection fails
+pub fn serve_simulator(
+    mut device: DeviceSimulator,
+    listener: UnixListener,
+) -> Result<(), DriverError> {
+    loop {
+        let (stream, _) = listener.accept()?;
+        log::info!("host connected");
+
+        if let Err(e) = serve_connection(&mut device, stream) {
+            log::error!("serving connection failed with Err {e}");
+        }
+
+        log::info!("host disconnected");
+    }
+}
+
+fn serve_connection(
+    device: &mut DeviceSimulator,
+    mut stream: UnixStream,
+) -> Result<(), DriverError> {
+    let mut frame = [0_u8; USB_HOST_MSG_LEN];
+
+    loop {
+        match stream.read_exact(&mut frame) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+
+        if let Err(e) = device.handle_host_frame(&frame) {
+            log::warn!("simulator failed to handle host frame with Err {e}");
+        }
+
+        while let Some(device_frame) = device.next_device_frame() {
+            stream.write_all(&device_frame)?;
+        }
+    }
+}
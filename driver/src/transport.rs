@@ -0,0 +1,315 @@
+use std::time::Duration;
+
+use crate::connection::USB_HOST_MSG_LEN;
+use crate::{Capabilities, DeviceMessage, DriverError, Features, HostMessage, TransferStatus};
+
+/// A link to the device over which host messages are sent and device messages are received.
+///
+/// Implementors only need to provide the single message primitives,
+/// the data transfer methods are built on top of them.
+pub trait Transport {
+    /// Sends a host message.
+    /// Blocks until finished
+    fn send_host_message(&self, msg: HostMessage, timeout: Duration) -> Result<(), DriverError>;
+
+    /// Reads a message from the device.
+    /// Blocks until finished
+    fn read_device_message(&self, timeout: Duration) -> Result<DeviceMessage, DriverError>;
+
+    /// The capabilities negotiated with the device when connecting.
+    /// Transports without a handshake only support the original messages
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::legacy()
+    }
+
+    /// Transmits the entire slice to the device with data messages,
+    /// followed by a DataComplete message with the CRC32 of the transfer.
+    ///
+    /// If the device supports [Features::TRANSFER_LENGTH] the length of the data is declared up front
+    /// and the CRC32 covers only the data. Otherwise it covers the zero padded payloads,
+    /// and a final zero padded data message is always sent, even for an exact multiple of the payload length.
+    /// Waits for the acknowledgement if the device supports [Features::CHECKSUM].
+    /// Blocks until finished
+    fn transmit_host_data(&self, data: &[u8], timeout: Duration) -> Result<(), DriverError> {
+        let features = self.capabilities().features;
+        let with_length = features.contains(Features::TRANSFER_LENGTH);
+
+        if with_length {
+            self.send_host_message(
+                HostMessage::DataLength {
+                    len: data.len() as u32,
+                },
+                timeout,
+            )?;
+        }
+
+        let padding_chunk =
+            (!with_length && data.len().is_multiple_of(USB_HOST_MSG_LEN - 1)).then_some(&[][..]);
+        let mut crc = crc32fast::Hasher::new();
+
+        for chunk in data.chunks(USB_HOST_MSG_LEN - 1).chain(padding_chunk) {
+            let mut payload = [0x00; USB_HOST_MSG_LEN - 1];
+            payload[..chunk.len()].copy_from_slice(chunk);
+            // Without the declared length the device can't tell the padding apart from the data
+            crc.update(if with_length { chunk } else { &payload });
+
+            self.send_host_message(HostMessage::Data { data: payload }, timeout)?;
+        }
+
+        self.send_host_message(
+            HostMessage::DataComplete {
+                crc32: crc.finalize(),
+            },
+            timeout,
+        )?;
+
+        if !features.contains(Features::CHECKSUM) {
+            return Ok(());
+        }
+
+        match self.read_device_message(timeout)? {
+            DeviceMessage::TransferAck(TransferStatus::Ok) => Ok(()),
+            DeviceMessage::TransferAck(TransferStatus::ChecksumMismatch) => {
+                Err(DriverError::ChecksumMismatch)
+            }
+            msg => Err(DriverError::UnexpectedMessage {
+                expected: "TransferAck",
+                actual: msg.variant_name(),
+            }),
+        }
+    }
+
+    /// Reads data from the device until a DataComplete Message or the optionally specified number of messages are received.
+    ///
+    /// If the device declares the length of the data, the padding of the last data message is trimmed.
+    /// Blocks until finished
+    fn receive_device_data(
+        &self,
+        timeout: Duration,
+        msg_cnt: Option<usize>,
+    ) -> Result<Vec<u8>, DriverError> {
+        let mut accumulated_data = vec![];
+        let mut declared_len = None;
+
+        let msg_cnt = msg_cnt.unwrap_or(usize::MAX);
+
+        for _ in 0..msg_cnt {
+            match self.read_device_message(timeout)? {
+                DeviceMessage::DataLength { len } => declared_len = Some(len as usize),
+                DeviceMessage::Data { data } => accumulated_data.extend_from_slice(&data),
+                DeviceMessage::DataComplete => break,
+                msg => {
+                    return Err(DriverError::UnexpectedMessage {
+                        expected: "Data",
+                        actual: msg.variant_name(),
+                    })
+                }
+            }
+        }
+
+        if let Some(len) = declared_len {
+            if accumulated_data.len() < len {
+                return Err(DriverError::TransferTooShort {
+                    expected: len,
+                    len: accumulated_data.len(),
+                });
+            }
+            accumulated_data.truncate(len);
+        }
+
+        Ok(accumulated_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    use super::Transport;
+    use crate::connection::USB_HOST_MSG_LEN;
+    use crate::{Capabilities, DeviceMessage, DriverError, Features, HostMessage, TransferStatus};
+
+    #[derive(Default)]
+    struct RecordingTransport {
+        sent: RefCell<Vec<HostMessage>>,
+        to_receive: RefCell<VecDeque<DeviceMessage>>,
+        capabilities: Capabilities,
+    }
+
+    impl Transport for RecordingTransport {
+        fn send_host_message(
+            &self,
+            msg: HostMessage,
+            _timeout: Duration,
+        ) -> Result<(), DriverError> {
+            self.sent.borrow_mut().push(msg);
+            Ok(())
+        }
+
+        fn read_device_message(&self, _timeout: Duration) -> Result<DeviceMessage, DriverError> {
+            self.to_receive
+                .borrow_mut()
+                .pop_front()
+                .ok_or(DriverError::UsbTimeout)
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            self.capabilities
+        }
+    }
+
+    #[test]
+    fn transmit_host_data_chunks_and_pads() {
+        let transport = RecordingTransport::default();
+        let data = (0..100).collect::<Vec<u8>>();
+
+        transport.transmit_host_data(&data, Duration::ZERO).unwrap();
+
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 3);
+
+        let mut received = vec![];
+        for msg in sent[..2].iter() {
+            match msg {
+                HostMessage::Data { data } => received.extend_from_slice(data),
+                msg => panic!("unexpected host message `{msg:?}`"),
+            }
+        }
+        assert_eq!(received.len(), 2 * (USB_HOST_MSG_LEN - 1));
+        assert_eq!(&received[..100], &data[..]);
+        assert!(received[100..].iter().all(|b| *b == 0x00));
+
+        // The checksum covers the padding
+        assert_eq!(
+            sent[2],
+            HostMessage::DataComplete {
+                crc32: crc32fast::hash(&received)
+            }
+        );
+    }
+
+    #[test]
+    fn transmit_host_data_declares_length() {
+        const PAYLOAD_LEN: usize = USB_HOST_MSG_LEN - 1;
+
+        // (data length, data messages without and with the declared length)
+        for (len, legacy_msg_cnt, msg_cnt) in [
+            (0, 1, 0),
+            (PAYLOAD_LEN - 1, 1, 1),
+            (PAYLOAD_LEN, 2, 1),
+            (PAYLOAD_LEN + 1, 2, 2),
+            (2 * PAYLOAD_LEN, 3, 2),
+        ] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<u8>>();
+
+            let legacy = RecordingTransport::default();
+            legacy.transmit_host_data(&data, Duration::ZERO).unwrap();
+            assert_eq!(legacy.sent.borrow().len(), legacy_msg_cnt + 1, "{len}");
+
+            let transport = RecordingTransport {
+                capabilities: Capabilities {
+                    features: Features::TRANSFER_LENGTH,
+                    ..Capabilities::legacy()
+                },
+                ..Default::default()
+            };
+            transport.transmit_host_data(&data, Duration::ZERO).unwrap();
+
+            let sent = transport.sent.borrow();
+            assert_eq!(sent.len(), msg_cnt + 2, "{len}");
+            assert_eq!(sent[0], HostMessage::DataLength { len: len as u32 });
+            // The checksum covers only the declared data, not the padding
+            assert_eq!(
+                sent.last(),
+                Some(&HostMessage::DataComplete {
+                    crc32: crc32fast::hash(&data)
+                }),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn transmit_host_data_waits_for_ack() {
+        let transport = RecordingTransport {
+            capabilities: Capabilities {
+                features: Features::CHECKSUM,
+                ..Capabilities::legacy()
+            },
+            ..Default::default()
+        };
+
+        transport.to_receive.borrow_mut().extend([
+            DeviceMessage::TransferAck(TransferStatus::Ok),
+            DeviceMessage::TransferAck(TransferStatus::ChecksumMismatch),
+        ]);
+        transport
+            .transmit_host_data(&[0xaa], Duration::ZERO)
+            .unwrap();
+        assert!(matches!(
+            transport.transmit_host_data(&[0xaa], Duration::ZERO),
+            Err(DriverError::ChecksumMismatch)
+        ));
+
+        // The device never answered
+        assert!(matches!(
+            transport.transmit_host_data(&[0xaa], Duration::ZERO),
+            Err(DriverError::UsbTimeout)
+        ));
+    }
+
+    #[test]
+    fn receive_device_data_stops_at_data_complete() {
+        let transport = RecordingTransport::default();
+        transport.to_receive.borrow_mut().extend([
+            DeviceMessage::Data {
+                data: [0xaa; USB_HOST_MSG_LEN - 1],
+            },
+            DeviceMessage::DataComplete,
+            DeviceMessage::Data {
+                data: [0xbb; USB_HOST_MSG_LEN - 1],
+            },
+        ]);
+
+        let data = transport.receive_device_data(Duration::ZERO, None).unwrap();
+
+        assert_eq!(data, vec![0xaa; USB_HOST_MSG_LEN - 1]);
+        assert_eq!(transport.to_receive.borrow().len(), 1);
+    }
+
+    #[test]
+    fn receive_device_data_trims_to_declared_length() {
+        let transport = RecordingTransport::default();
+        transport.to_receive.borrow_mut().extend([
+            DeviceMessage::DataLength { len: 64 },
+            DeviceMessage::Data {
+                data: [0xaa; USB_HOST_MSG_LEN - 1],
+            },
+            DeviceMessage::Data {
+                data: [0xbb; USB_HOST_MSG_LEN - 1],
+            },
+            DeviceMessage::DataComplete,
+        ]);
+
+        let data = transport.receive_device_data(Duration::ZERO, None).unwrap();
+        assert_eq!(data.len(), 64);
+        assert_eq!(data[63], 0xbb);
+
+        transport.to_receive.borrow_mut().extend([
+            DeviceMessage::DataLength { len: 64 },
+            DeviceMessage::Data {
+                data: [0xaa; USB_HOST_MSG_LEN - 1],
+            },
+            DeviceMessage::DataComplete,
+        ]);
+        assert!(matches!(
+            transport.receive_device_data(Duration::ZERO, None),
+            Err(DriverError::TransferTooShort {
+                expected: 64,
+                len: 63
+            })
+        ));
+    }
+}
@@ -0,0 +1,720 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use deskassistant_driver::connection::USB_HOST_MSG_LEN;
+use deskassistant_driver::simulator::{DeviceSimulator, SimulatorConnection};
+use deskassistant_driver::{
+    actions, compression, BitDepth, Capabilities, DeviceMessage, DeviceSettings, DeviceStatus,
+    DisplayColors, DriverError, Encoding, EpdImage, EpdImageFormat, EpdPage, ExportOptions,
+    Features, HostMessage, Region, TransferStatus, Transport, EPD_HEIGHT, EPD_WIDTH,
+    PROTOCOL_VERSION,
+};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+fn test_image_file(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../test_images")
+        .join(name)
+}
+
+fn epd_format() -> EpdImageFormat {
+    EpdImageFormat {
+        width: EPD_WIDTH,
+        height: EPD_HEIGHT,
+        colors: DisplayColors::BlackWhite,
+        bit_depth: BitDepth::Bpp1,
+    }
+}
+
+#[test]
+fn retreive_device_status() {
+    let connection = SimulatorConnection::default();
+
+    let status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+
+    assert_eq!(status.current_epd_page, EpdPage::Overview);
+    assert_eq!(status.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(status.display_width, Some(EPD_WIDTH as u16));
+    assert_eq!(status.display_height, Some(EPD_HEIGHT as u16));
+    assert_eq!(status.app_image_cnt, Some(0));
+    assert_eq!(status.displayed_app, None);
+
+    actions::update_app_image_from_file(
+        &connection,
+        String::from("firefox"),
+        test_image_file("app_images/firefox.png"),
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+    actions::report_active_app(&connection, String::from("firefox"), TIMEOUT).unwrap();
+
+    let updated_status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+    assert_eq!(updated_status.app_image_cnt, Some(1));
+    assert_eq!(updated_status.displayed_app.as_deref(), Some("firefox"));
+    assert!(updated_status.storage_free_kib < status.storage_free_kib);
+}
+
+#[test]
+fn refresh_display() {
+    let connection = SimulatorConnection::default();
+
+    actions::refresh_display(&connection, TIMEOUT).unwrap();
+
+    assert_eq!(connection.device().display_refresh_cnt(), 1);
+}
+
+#[test]
+fn switch_page() {
+    let connection = SimulatorConnection::default();
+
+    actions::switch_page(&connection, EpdPage::UserImage, TIMEOUT).unwrap();
+    assert_eq!(connection.device().current_epd_page(), EpdPage::UserImage);
+
+    let status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+    assert_eq!(status.current_epd_page, EpdPage::UserImage);
+}
+
+#[test]
+fn update_user_image_from_file() {
+    let connection = SimulatorConnection::default();
+    let img_file = test_image_file("app_images/firefox.png");
+
+    actions::update_user_image_from_file(
+        &connection,
+        img_file.clone(),
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    let format = epd_format();
+    let expected = EpdImage::load_from_file(&img_file)
+        .unwrap()
+        .export(&format, &ExportOptions::default())
+        .unwrap();
+
+    let device = connection.device();
+    let user_image = device.user_image().unwrap();
+    assert_eq!(user_image.format, format);
+    assert_eq!(user_image.data, expected);
+}
+
+#[test]
+fn update_user_image_from_missing_file() {
+    let connection = SimulatorConnection::default();
+
+    assert!(matches!(
+        actions::update_user_image_from_file(
+            &connection,
+            test_image_file("does_not_exist.png"),
+            &ExportOptions::default(),
+            TIMEOUT
+        ),
+        Err(DriverError::FileNotFound(_))
+    ));
+    assert!(connection.device().user_image().is_none());
+}
+
+#[test]
+fn update_user_image_from_memory() {
+    let connection = SimulatorConnection::default();
+    let img_file = test_image_file("app_images/code.png");
+    let png = std::fs::read(&img_file).unwrap();
+
+    actions::update_user_image(
+        &connection,
+        EpdImage::load_from_memory(&png).unwrap(),
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    let expected = EpdImage::load_from_file(&img_file)
+        .unwrap()
+        .export(&epd_format(), &ExportOptions::default())
+        .unwrap();
+
+    assert_eq!(connection.device().user_image().unwrap().data, expected);
+}
+
+#[test]
+fn update_app_image_from_memory() {
+    let connection = SimulatorConnection::default();
+    let image = EpdImage::load_from_data(2, 2, vec![0xff; 12]).unwrap();
+
+    actions::update_app_image(
+        &connection,
+        String::from("generated"),
+        image,
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    let device = connection.device();
+    let app_image = &device.app_images()["generated"];
+    // a white image stays white
+    assert!(app_image.data.iter().all(|&byte| byte == 0xff));
+}
+
+#[test]
+fn update_app_image_from_file() {
+    let connection = SimulatorConnection::default();
+
+    actions::update_app_image_from_file(
+        &connection,
+        String::from("firefox"),
+        test_image_file("app_images/firefox.png"),
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+    actions::update_app_image_from_file(
+        &connection,
+        String::from("code"),
+        test_image_file("app_images/code.png"),
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    let device = connection.device();
+    let app_images = device.app_images();
+    assert_eq!(app_images.len(), 2);
+    assert_eq!(app_images["firefox"].format, epd_format());
+    assert_eq!(
+        app_images["code"].data.len(),
+        (EPD_WIDTH * EPD_HEIGHT / 8) as usize
+    );
+}
+
+#[test]
+fn report_active_app() {
+    let connection = SimulatorConnection::default();
+
+    actions::report_active_app(&connection, String::from("gnome-shell"), TIMEOUT).unwrap();
+
+    assert_eq!(connection.device().active_app(), Some("gnome-shell"));
+}
+
+#[test]
+fn transfer_lengths() {
+    const PAYLOAD_LEN: usize = USB_HOST_MSG_LEN - 1;
+
+    // The app name is sent with its nul terminator
+    for connection in [
+        SimulatorConnection::default(),
+        SimulatorConnection::new(DeviceSimulator::legacy()),
+    ] {
+        for len in [
+            PAYLOAD_LEN - 2,
+            PAYLOAD_LEN - 1,
+            PAYLOAD_LEN,
+            2 * PAYLOAD_LEN - 1,
+        ] {
+            let app_name = "a".repeat(len);
+            actions::report_active_app(&connection, app_name.clone(), TIMEOUT).unwrap();
+            assert_eq!(connection.device().active_app(), Some(app_name.as_str()));
+        }
+    }
+
+    // Pad the settings so that their JSON hits every length around a multiple of the payload length
+    let connection = SimulatorConnection::default();
+    for pad_len in 0..=PAYLOAD_LEN {
+        let mut settings = DeviceSettings::default();
+        settings.values.insert(
+            String::from("padding"),
+            serde_json::Value::String("x".repeat(pad_len)),
+        );
+
+        actions::update_settings(&connection, &settings, TIMEOUT).unwrap();
+        assert_eq!(
+            actions::retreive_settings(&connection, TIMEOUT).unwrap(),
+            settings
+        );
+    }
+
+    // A string that is longer than the transferred data is rejected instead of padded
+    let connection = SimulatorConnection::default();
+    connection
+        .send_host_message(HostMessage::ReportActiveApp { str_len: 10 }, TIMEOUT)
+        .unwrap();
+    assert!(matches!(
+        connection.transmit_host_data(b"abc\0", TIMEOUT),
+        Err(DriverError::TransferTooShort {
+            expected: 10,
+            len: 4
+        })
+    ));
+    assert_eq!(connection.device().active_app(), None);
+}
+
+#[test]
+fn corrupted_transfer() {
+    let connection = SimulatorConnection::default();
+
+    let mut data = [0x00; USB_HOST_MSG_LEN - 1];
+    data[..11].copy_from_slice(b"gnome-shell");
+    connection
+        .send_host_message(HostMessage::ReportActiveApp { str_len: 11 }, TIMEOUT)
+        .unwrap();
+    connection
+        .send_host_message(HostMessage::Data { data }, TIMEOUT)
+        .unwrap();
+    connection
+        .send_host_message(
+            HostMessage::DataComplete {
+                crc32: crc32fast::hash(&data) ^ 1,
+            },
+            TIMEOUT,
+        )
+        .unwrap();
+
+    assert_eq!(
+        connection.read_device_message(TIMEOUT).unwrap(),
+        DeviceMessage::TransferAck(TransferStatus::ChecksumMismatch)
+    );
+    assert_eq!(connection.device().active_app(), None);
+
+    // The next transfer is intact again
+    actions::report_active_app(&connection, String::from("gnome-shell"), TIMEOUT).unwrap();
+    assert_eq!(connection.device().active_app(), Some("gnome-shell"));
+}
+
+#[test]
+fn retreive_app_images_list() {
+    let connection = SimulatorConnection::default();
+
+    assert!(actions::retreive_app_images_list(&connection, TIMEOUT)
+        .unwrap()
+        .is_empty());
+
+    for app_name in ["gnome-shell", "firefox", "code"] {
+        actions::update_app_image_from_file(
+            &connection,
+            app_name.to_string(),
+            test_image_file(&format!("app_images/{app_name}.png")),
+            &ExportOptions::default(),
+            TIMEOUT,
+        )
+        .unwrap();
+    }
+
+    let app_images_list = actions::retreive_app_images_list(&connection, TIMEOUT).unwrap();
+    assert_eq!(app_images_list, vec!["code", "firefox", "gnome-shell"]);
+}
+
+#[test]
+fn retreive_user_image() {
+    let connection = SimulatorConnection::default();
+
+    assert!(matches!(
+        actions::retreive_user_image(&connection, TIMEOUT),
+        Err(DriverError::ImageNotFound)
+    ));
+
+    let image = EpdImage::load_from_file(test_image_file("Mandrill.png")).unwrap();
+    actions::update_user_image(
+        &connection,
+        image.clone(),
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    let retreived = actions::retreive_user_image(&connection, TIMEOUT).unwrap();
+    let expected = image
+        .preview(&epd_format(), &ExportOptions::default())
+        .unwrap();
+    assert_eq!(retreived.dimensions(), (EPD_WIDTH, EPD_HEIGHT));
+    assert_eq!(
+        retreived.encode_png().unwrap(),
+        expected.encode_png().unwrap()
+    );
+}
+
+#[test]
+fn retreive_app_image() {
+    let connection = SimulatorConnection::default();
+
+    actions::update_app_image_from_file(
+        &connection,
+        String::from("firefox"),
+        test_image_file("app_images/firefox.png"),
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    let retreived =
+        actions::retreive_app_image(&connection, String::from("firefox"), TIMEOUT).unwrap();
+    let expected = EpdImage::load_from_file(test_image_file("app_images/firefox.png"))
+        .unwrap()
+        .preview(&epd_format(), &ExportOptions::default())
+        .unwrap();
+    assert_eq!(
+        retreived.encode_png().unwrap(),
+        expected.encode_png().unwrap()
+    );
+
+    assert!(matches!(
+        actions::retreive_app_image(&connection, String::from("code"), TIMEOUT),
+        Err(DriverError::ImageNotFound)
+    ));
+    // the connection is still usable afterwards
+    actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+}
+
+#[test]
+fn delete_and_rename_app_images() {
+    let connection = SimulatorConnection::default();
+
+    for app_name in ["gnome-shell", "firefox", "code"] {
+        actions::update_app_image_from_file(
+            &connection,
+            app_name.to_string(),
+            test_image_file(&format!("app_images/{app_name}.png")),
+            &ExportOptions::default(),
+            TIMEOUT,
+        )
+        .unwrap();
+    }
+
+    actions::delete_app_image(&connection, String::from("firefox"), TIMEOUT).unwrap();
+    // deleting a missing image does nothing
+    actions::delete_app_image(&connection, String::from("firefox"), TIMEOUT).unwrap();
+    assert_eq!(
+        actions::retreive_app_images_list(&connection, TIMEOUT).unwrap(),
+        vec!["code", "gnome-shell"]
+    );
+
+    let code_image = connection.device().app_images()["code"].clone();
+    actions::rename_app_image(
+        &connection,
+        String::from("code"),
+        String::from("codium"),
+        TIMEOUT,
+    )
+    .unwrap();
+    assert_eq!(
+        actions::retreive_app_images_list(&connection, TIMEOUT).unwrap(),
+        vec!["codium", "gnome-shell"]
+    );
+    assert_eq!(connection.device().app_images()["codium"], code_image);
+
+    actions::delete_all_app_images(&connection, TIMEOUT).unwrap();
+    assert!(actions::retreive_app_images_list(&connection, TIMEOUT)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn retreive_and_update_settings() {
+    let connection = SimulatorConnection::default();
+
+    let mut settings = actions::retreive_settings(&connection, TIMEOUT).unwrap();
+    assert_eq!(settings, DeviceSettings::default());
+
+    settings
+        .values
+        .insert(String::from("led_brightness"), serde_json::json!(3));
+    actions::update_settings(&connection, &settings, TIMEOUT).unwrap();
+
+    assert_eq!(
+        actions::retreive_settings(&connection, TIMEOUT).unwrap(),
+        settings
+    );
+
+    // invalid settings are not sent
+    settings.values.insert(
+        String::from("name"),
+        serde_json::Value::String("x".repeat(DeviceSettings::JSON_LEN_MAX)),
+    );
+    assert!(matches!(
+        actions::update_settings(&connection, &settings, TIMEOUT),
+        Err(DriverError::InvalidSetting(_))
+    ));
+    assert_eq!(
+        actions::retreive_settings(&connection, TIMEOUT)
+            .unwrap()
+            .get("led_brightness")
+            .unwrap(),
+        3
+    );
+}
+
+#[test]
+fn handshake() {
+    let connection = SimulatorConnection::default();
+
+    let capabilities = connection.capabilities();
+    assert_eq!(capabilities.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(capabilities.features, Features::ALL);
+    assert_eq!(capabilities.epd_format(), epd_format());
+}
+
+#[test]
+fn reported_display_geometry() {
+    let connection = SimulatorConnection::new(DeviceSimulator::new().with_display(
+        296,
+        128,
+        DisplayColors::BlackWhiteRed,
+    ));
+
+    let capabilities = connection.capabilities();
+    assert_eq!(
+        (capabilities.display_width, capabilities.display_height),
+        (296, 128)
+    );
+    assert_eq!(capabilities.display_colors, DisplayColors::BlackWhiteRed);
+
+    // Images are exported for the reported display
+    let image = EpdImage::load_from_data(10, 10, vec![0x00; 10 * 10 * 3]).unwrap();
+    actions::update_user_image(&connection, image, &ExportOptions::default(), TIMEOUT).unwrap();
+
+    let format = connection.device().user_image().unwrap().format;
+    assert_eq!((format.width, format.height), (296, 128));
+    assert_eq!(
+        actions::retreive_user_image(&connection, TIMEOUT)
+            .unwrap()
+            .dimensions(),
+        (296, 128)
+    );
+}
+
+#[test]
+fn tri_color_display() {
+    let connection = SimulatorConnection::new(DeviceSimulator::new().with_display(
+        EPD_WIDTH as u16,
+        EPD_HEIGHT as u16,
+        DisplayColors::BlackWhiteYellow,
+    ));
+
+    let image = EpdImage::load_from_data(4, 3, [0xff, 0xff, 0x00].repeat(4 * 3)).unwrap();
+    actions::update_app_image(
+        &connection,
+        "sunny".to_string(),
+        image,
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+
+    let format = connection.device().app_images()["sunny"].format;
+    assert_eq!(format.colors, DisplayColors::BlackWhiteYellow);
+    assert_eq!(
+        connection.device().app_images()["sunny"].data.len(),
+        format.packed_len()
+    );
+
+    let retreived = actions::retreive_app_image(&connection, "sunny".to_string(), TIMEOUT)
+        .unwrap()
+        .encode_png()
+        .unwrap();
+    let retreived = image::load_from_memory(&retreived).unwrap().into_rgb8();
+    assert!(retreived.pixels().all(|px| px.0 == [0xff, 0xff, 0x00]));
+}
+
+#[test]
+fn grayscale_image() {
+    let connection = SimulatorConnection::default();
+
+    let gradient = (0..EPD_WIDTH * EPD_HEIGHT)
+        .flat_map(|i| [((i % EPD_WIDTH) * 0xff / EPD_WIDTH) as u8; 3])
+        .collect();
+    let image = EpdImage::load_from_data(EPD_WIDTH, EPD_HEIGHT, gradient).unwrap();
+    let options = ExportOptions {
+        bit_depth: BitDepth::Bpp2,
+        ..Default::default()
+    };
+    actions::update_user_image(&connection, image, &options, TIMEOUT).unwrap();
+
+    let stored = connection.device().user_image().unwrap().clone();
+    assert_eq!(stored.format.bit_depth, BitDepth::Bpp2);
+    assert_eq!(stored.data.len(), (EPD_WIDTH * EPD_HEIGHT / 4) as usize);
+
+    let retreived = actions::retreive_user_image(&connection, TIMEOUT)
+        .unwrap()
+        .encode_png()
+        .unwrap();
+    let retreived = image::load_from_memory(&retreived).unwrap().into_luma8();
+    let mut levels = retreived.pixels().map(|px| px.0[0]).collect::<Vec<u8>>();
+    levels.sort_unstable();
+    levels.dedup();
+    assert_eq!(levels, vec![0x00, 0x55, 0xaa, 0xff]);
+}
+
+#[test]
+fn update_user_image_region() {
+    let white = vec![0xff; (EPD_WIDTH * EPD_HEIGHT * 3) as usize];
+    let mut changed = white.clone();
+    // a black 10x10 px square at (100, 50)
+    for y in 50..60 {
+        for x in 100..110 {
+            let i = ((y * EPD_WIDTH + x) * 3) as usize;
+            changed[i..i + 3].fill(0x00);
+        }
+    }
+    let white = EpdImage::load_from_data(EPD_WIDTH, EPD_HEIGHT, white).unwrap();
+    let changed = EpdImage::load_from_data(EPD_WIDTH, EPD_HEIGHT, changed).unwrap();
+    let options = ExportOptions::default();
+
+    let connection = SimulatorConnection::default();
+    let mut last_sent = None;
+
+    // Without a last sent image the whole image is sent
+    let region = actions::update_user_image_region(
+        &connection,
+        white.clone(),
+        &mut last_sent,
+        &options,
+        TIMEOUT,
+    )
+    .unwrap();
+    assert_eq!(region, Some(Region::full(&epd_format())));
+
+    let region = actions::update_user_image_region(
+        &connection,
+        changed.clone(),
+        &mut last_sent,
+        &options,
+        TIMEOUT,
+    )
+    .unwrap();
+    assert_eq!(
+        region,
+        Some(Region {
+            x: 96,
+            y: 50,
+            width: 16,
+            height: 10
+        })
+    );
+    let expected = changed.clone().export(&epd_format(), &options).unwrap();
+    assert_eq!(connection.device().user_image().unwrap().data, expected);
+
+    // Nothing changed
+    let region = actions::update_user_image_region(
+        &connection,
+        changed.clone(),
+        &mut last_sent,
+        &options,
+        TIMEOUT,
+    )
+    .unwrap();
+    assert_eq!(region, None);
+
+    // A failed export sends nothing and keeps the last sent image
+    let invalid_options = ExportOptions {
+        gamma: 0.0,
+        ..Default::default()
+    };
+    assert!(actions::update_user_image_region(
+        &connection,
+        white.clone(),
+        &mut last_sent,
+        &invalid_options,
+        TIMEOUT,
+    )
+    .is_err());
+    assert!(last_sent.is_some());
+
+    // Firmware that can't update regions gets the whole image
+    let connection = SimulatorConnection::new(DeviceSimulator::legacy());
+    let mut last_sent = None;
+    for image in [white, changed] {
+        let region = actions::update_user_image_region(
+            &connection,
+            image,
+            &mut last_sent,
+            &options,
+            TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(region, Some(Region::full(&epd_format())));
+    }
+    assert_eq!(connection.device().user_image().unwrap().data, expected);
+}
+
+#[test]
+fn compressed_transfer() {
+    let image = EpdImage::load_from_file(test_image_file("app_images/firefox.png")).unwrap();
+    let options = ExportOptions::default();
+    let expected = image.clone().export(&epd_format(), &options).unwrap();
+
+    // App images are mostly white
+    let (encoding, encoded) = compression::encode_smallest(&expected, Features::ALL);
+    assert_ne!(encoding, Encoding::Raw);
+    assert!(encoded.len() < expected.len() / 2);
+
+    // Compressed for the simulator, raw for legacy firmware
+    for connection in [
+        SimulatorConnection::default(),
+        SimulatorConnection::new(DeviceSimulator::legacy()),
+    ] {
+        actions::update_user_image(&connection, image.clone(), &options, TIMEOUT).unwrap();
+        assert_eq!(connection.device().user_image().unwrap().data, expected);
+    }
+}
+
+#[test]
+fn legacy_firmware() {
+    let connection = SimulatorConnection::new(DeviceSimulator::legacy());
+    assert_eq!(connection.capabilities(), Capabilities::legacy());
+    // The Hello of the handshake was discarded without an answer
+    assert!(connection.device().next_device_frame().is_none());
+
+    // The original messages still work
+    actions::switch_page(&connection, EpdPage::UserImage, TIMEOUT).unwrap();
+    let status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+    assert_eq!(status, DeviceStatus::short(EpdPage::UserImage));
+
+    // Newer ones fail without being sent
+    assert!(matches!(
+        actions::retreive_settings(&connection, TIMEOUT),
+        Err(DriverError::Unsupported { .. })
+    ));
+    assert!(matches!(
+        actions::delete_all_app_images(&connection, TIMEOUT),
+        Err(DriverError::Unsupported { .. })
+    ));
+    assert!(matches!(
+        actions::retreive_user_image(&connection, TIMEOUT),
+        Err(DriverError::Unsupported { .. })
+    ));
+    let options = ExportOptions {
+        bit_depth: BitDepth::Bpp2,
+        ..Default::default()
+    };
+    assert!(matches!(
+        actions::update_user_image_from_file(
+            &connection,
+            test_image_file("Mandrill.png"),
+            &options,
+            TIMEOUT
+        ),
+        Err(DriverError::Unsupported { .. })
+    ));
+}
+
+#[test]
+fn legacy_firmware_discards_unknown_variants() {
+    let mut device = DeviceSimulator::legacy();
+
+    for variant in 0x09..=u8::MAX {
+        let mut frame = [0x00; USB_HOST_MSG_LEN];
+        frame[0] = variant;
+
+        assert!(matches!(
+            device.handle_host_frame(&frame),
+            Err(DriverError::InvalidMessageVariant(v)) if v == variant
+        ));
+        assert!(device.next_device_frame().is_none());
+    }
+
+    let connection = SimulatorConnection::new(device);
+    let status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+    assert_eq!(status, DeviceStatus::short(EpdPage::Overview));
+}
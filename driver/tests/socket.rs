@@ -0,0 +1,225 @@
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use deskassistant_driver::capabilities::HANDSHAKE_TIMEOUT;
+use deskassistant_driver::connection::USB_HOST_MSG_LEN;
+use deskassistant_driver::simulator::DeviceSimulator;
+use deskassistant_driver::socket::{self, SocketConnection};
+use deskassistant_driver::{
+    actions, Capabilities, DeviceConnection, DeviceEndpoint, DriverError, EpdImage, EpdPage,
+    ExportOptions, Features, Region, Transport, EPD_HEIGHT, EPD_WIDTH,
+};
+
+const TIMEOUT: Duration = Duration::from_millis(1_000);
+
+fn spawn_simulator(name: &str) -> PathBuf {
+    spawn_device(name, DeviceSimulator::new())
+}
+
+fn spawn_device(name: &str, device: DeviceSimulator) -> PathBuf {
+    let socket_path = std::env::temp_dir().join(format!(
+        "deskassistant-test-{}-{name}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&socket_path);
+
+    let listener = UnixListener::bind(&socket_path).unwrap();
+    std::thread::spawn(move || socket::serve_simulator(device, listener));
+
+    socket_path
+}
+
+/// Serves a new simulated device for a single connection, like a freshly started `deskassistant-sim`.
+/// Shutting down the received device end of the connection disconnects it
+fn spawn_device_once(socket_path: &Path) -> mpsc::Receiver<UnixStream> {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).unwrap();
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        sender.send(stream.try_clone().unwrap()).unwrap();
+
+        let mut device = DeviceSimulator::new();
+        let mut frame = [0_u8; USB_HOST_MSG_LEN];
+        while stream.read_exact(&mut frame).is_ok() {
+            let _ = device.handle_host_frame(&frame);
+            while let Some(device_frame) = device.next_device_frame() {
+                if stream.write_all(&device_frame).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    receiver
+}
+
+#[test]
+fn connect_fails_without_listener() {
+    let mut connection = SocketConnection::new(std::env::temp_dir().join("does-not-exist.sock"));
+    connection.handle_events().unwrap();
+
+    assert!(!connection.is_connected());
+    assert!(matches!(
+        actions::refresh_display(&connection, TIMEOUT),
+        Err(DriverError::NotConnected)
+    ));
+}
+
+#[test]
+fn actions_over_socket() {
+    let socket_path = spawn_simulator("actions");
+
+    let mut connection = SocketConnection::new(&socket_path);
+    assert!(connection.handle_events().unwrap());
+    assert!(connection.is_connected());
+    assert!(!connection.handle_events().unwrap());
+    assert_eq!(connection.capabilities().features, Features::ALL);
+
+    actions::switch_page(&connection, EpdPage::AppScreen, TIMEOUT).unwrap();
+    let status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+    assert_eq!(status.current_epd_page, EpdPage::AppScreen);
+
+    actions::update_app_image_from_file(
+        &connection,
+        String::from("firefox"),
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../test_images/app_images/firefox.png"),
+        &ExportOptions::default(),
+        TIMEOUT,
+    )
+    .unwrap();
+    assert_eq!(
+        actions::retreive_app_images_list(&connection, TIMEOUT).unwrap(),
+        vec!["firefox"]
+    );
+
+    // The simulated device keeps its state across connections
+    drop(connection);
+    let mut connection = SocketConnection::new(&socket_path);
+    connection.handle_events().unwrap();
+
+    let status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+    assert_eq!(status.current_epd_page, EpdPage::AppScreen);
+
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn zero_timeout() {
+    let socket_path = spawn_simulator("zero-timeout");
+
+    let mut connection = SocketConnection::new(&socket_path);
+    connection.handle_events().unwrap();
+
+    // Like with usb, a zero timeout waits without a limit
+    actions::switch_page(&connection, EpdPage::UserImage, Duration::ZERO).unwrap();
+    let status = actions::retreive_device_status(&connection, Duration::ZERO).unwrap();
+    assert_eq!(status.current_epd_page, EpdPage::UserImage);
+
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn reconnects_after_device_restart() {
+    let socket_path = std::env::temp_dir().join(format!(
+        "deskassistant-test-{}-restart.sock",
+        std::process::id()
+    ));
+
+    let device_end = spawn_device_once(&socket_path);
+    let mut connection = SocketConnection::new(&socket_path);
+    assert!(connection.handle_events().unwrap());
+    actions::switch_page(&connection, EpdPage::AppScreen, TIMEOUT).unwrap();
+
+    // The device goes away, which is noticed by the next action
+    device_end.recv().unwrap().shutdown(Shutdown::Both).unwrap();
+    assert!(actions::retreive_device_status(&connection, TIMEOUT).is_err());
+    assert!(!connection.is_connected());
+    assert!(matches!(
+        actions::refresh_display(&connection, TIMEOUT),
+        Err(DriverError::NotConnected)
+    ));
+
+    // Once it is back, the connection reports the change and connects to it again
+    let _device_end = spawn_device_once(&socket_path);
+    assert!(connection.handle_events().unwrap());
+    assert!(connection.is_connected());
+    assert_eq!(connection.capabilities().features, Features::ALL);
+    let status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+    assert_eq!(status.current_epd_page, EpdPage::Overview);
+
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn full_user_image_after_device_restart() {
+    let socket_path = std::env::temp_dir().join(format!(
+        "deskassistant-test-{}-restart-region.sock",
+        std::process::id()
+    ));
+    let white = vec![0xff; (EPD_WIDTH * EPD_HEIGHT * 3) as usize];
+    let mut changed = white.clone();
+    changed[..3].fill(0x00);
+    let white = EpdImage::load_from_data(EPD_WIDTH, EPD_HEIGHT, white).unwrap();
+    let changed = EpdImage::load_from_data(EPD_WIDTH, EPD_HEIGHT, changed).unwrap();
+    let full = Region {
+        x: 0,
+        y: 0,
+        width: EPD_WIDTH,
+        height: EPD_HEIGHT,
+    };
+
+    let options = ExportOptions::default();
+
+    // Tracks the last sent image like the python bindings do
+    let device_end = spawn_device_once(&socket_path);
+    let mut connection = DeviceConnection::new(&DeviceEndpoint::Unix(socket_path.clone())).unwrap();
+    let mut last_sent = None;
+    assert!(connection.handle_events().unwrap());
+
+    let region =
+        actions::update_user_image_region(&connection, white, &mut last_sent, &options, TIMEOUT)
+            .unwrap();
+    assert_eq!(region, Some(full));
+
+    // The restarted device has no user image, so the whole image is sent instead of the changed region
+    device_end.recv().unwrap().shutdown(Shutdown::Both).unwrap();
+    assert!(actions::retreive_device_status(&connection, TIMEOUT).is_err());
+    let _device_end = spawn_device_once(&socket_path);
+    if connection.handle_events().unwrap() {
+        last_sent = None;
+    }
+    assert!(connection.is_connected());
+
+    let region =
+        actions::update_user_image_region(&connection, changed, &mut last_sent, &options, TIMEOUT)
+            .unwrap();
+    assert_eq!(region, Some(full));
+
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn connecting_does_not_wait_for_handshake() {
+    let socket_path = spawn_device("legacy", DeviceSimulator::legacy());
+
+    // Legacy firmware never answers the handshake, which only delays the first message
+    let mut connection = SocketConnection::new(&socket_path);
+    let start = Instant::now();
+    assert!(connection.handle_events().unwrap());
+    assert!(start.elapsed() < HANDSHAKE_TIMEOUT);
+
+    actions::switch_page(&connection, EpdPage::UserImage, TIMEOUT).unwrap();
+    assert_eq!(connection.capabilities(), Capabilities::legacy());
+    let status = actions::retreive_device_status(&connection, TIMEOUT).unwrap();
+    assert_eq!(status.current_epd_page, EpdPage::UserImage);
+
+    let _ = std::fs::remove_file(&socket_path);
+}
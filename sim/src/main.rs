@@ -0,0 +1,17 @@
+// The simulated device is served on a unix socket, like the socket endpoint of the driver
+#[cfg(unix)]
+mod serve;
+
+#[cfg(unix)]
+fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+    log::debug!("init");
+
+    serve::run()
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("deskassistant-sim serves the simulated device on a unix socket, which is only supported on unix");
+    std::process::exit(1);
+}
@@ -0,0 +1,51 @@
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+use clap::Parser;
+use deskassistant_driver::simulator::DeviceSimulator;
+use deskassistant_driver::socket;
+use deskassistant_driver::{DisplayColors, EPD_HEIGHT, EPD_WIDTH};
+
+/// a simulated deskassistant device, served on a unix socket
+#[derive(Debug, Clone, Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    /// the path of the unix socket to listen on
+    #[clap(short, long, value_parser, default_value = "/tmp/deskassistant.sock")]
+    socket: PathBuf,
+    /// behave like firmware that predates the protocol handshake
+    #[clap(long, action)]
+    legacy: bool,
+    /// the width of the simulated display. Not reported with `--legacy`
+    #[clap(long, value_parser, default_value_t = EPD_WIDTH as u16)]
+    display_width: u16,
+    /// the height of the simulated display. Not reported with `--legacy`
+    #[clap(long, value_parser, default_value_t = EPD_HEIGHT as u16)]
+    display_height: u16,
+    /// the colours of the simulated display. Not reported with `--legacy`
+    #[clap(long, value_enum, default_value_t)]
+    display_colors: DisplayColors,
+}
+
+pub fn run() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    // Remove a stale socket left behind by a previous run
+    if std::fs::symlink_metadata(&cli.socket).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(&cli.socket)?;
+    }
+
+    let listener = UnixListener::bind(&cli.socket)?;
+    println!("simulated device listening on `{}`", cli.socket.display());
+
+    let device = if cli.legacy {
+        DeviceSimulator::legacy()
+    } else {
+        DeviceSimulator::new()
+    }
+    .with_display(cli.display_width, cli.display_height, cli.display_colors);
+    socket::serve_simulator(device, listener)?;
+
+    Ok(())
+}
@@ -7,7 +7,21 @@ from PySide6.QtCore import Qt, Slot, Signal, QTimer, QMargins
 from PySide6.QtGui import *
 from PySide6.QtWidgets import *
 
-from deskassistant_driver import EpdPage, PyUsbConnection, DeviceStatus
+from deskassistant_driver import (
+    EpdPage,
+    PyUsbConnection,
+    DeviceStatus,
+    Dithering,
+    FitMode,
+    BitDepth,
+    ExportOptions,
+    DriverError,
+    DeviceNotConnectedError,
+    DeviceTimeoutError,
+    ProtocolError,
+    ImageError,
+    preview_image_from_file,
+)
 
 import core
 
@@ -32,7 +46,12 @@ class AppWindow(QMainWindow):
 
         self.active_app_name = core.get_active_app_name()
 
-        self.device_connection = PyUsbConnection.new()
+        # Connect to a simulated device if an endpoint like `unix:/tmp/deskassistant.sock` is set
+        device = os.environ.get("DESKASSISTANT_DEVICE")
+        if device != None:
+            self.device_connection = PyUsbConnection.new_with_device(device)
+        else:
+            self.device_connection = PyUsbConnection.new()
 
         # Menu
         self.menu = self.menuBar()
@@ -68,9 +87,29 @@ class AppWindow(QMainWindow):
         device_connection_event_timer.timeout.connect(self.connection_handle_events)
         device_connection_event_timer.start()
 
+    def device_call(self, description: str, func, *args):
+        """Calls into the device connection and reports failures. Timeouts are retried once.
+        Returns None if the call failed."""
+        retries = 1
+        while True:
+            try:
+                return func(*args)
+            except DeviceTimeoutError:
+                if retries > 0:
+                    retries -= 1
+                    continue
+                self.status.showMessage(f"{description} failed: device timed out", 5000)
+            except DeviceNotConnectedError:
+                self.status.showMessage(f"{description} failed: device not connected", 5000)
+            except ImageError as e:
+                QMessageBox.warning(self, app_name, f"{description} failed: {e}")
+            except (ProtocolError, DriverError) as e:
+                self.status.showMessage(f"{description} failed: {e}", 5000)
+            return None
+
     @Slot()
     def connection_handle_events(self):
-        self.device_connection.handle_events()
+        self.device_call("Connecting", self.device_connection.handle_events)
 
         if self.device_connection.is_connected():
             self.central_widget.set_view(1)
@@ -87,7 +126,12 @@ class AppWindow(QMainWindow):
                 self.central_widget.status_widget.UpdateAppStatus()
 
                 if self.device_connection.is_connected():
-                    self.device_connection.report_active_app_name(active_app_name, 5000)
+                    self.device_call(
+                        "Reporting active app",
+                        self.device_connection.report_active_app_name,
+                        active_app_name,
+                        5000,
+                    )
 
 
 class AppCentralWidget(QWidget):
@@ -209,14 +253,23 @@ class DeviceControls(QGroupBox):
     def SwitchPage(self, page: EpdPage):
         if self.app_window.device_connection.is_connected():
             self.app_window.status.showMessage(f"Switching to page {page}", 2000)
-            self.app_window.device_connection.switch_page(page, 5000)
+            self.app_window.device_call(
+                "Switching page",
+                self.app_window.device_connection.switch_page,
+                page,
+                5000,
+            )
             self.app_window.central_widget.connected_view.status_page.StatusRefresh()
 
     @Slot()
     def DisplayRefresh(self):
         if self.app_window.device_connection.is_connected():
             self.app_window.status.showMessage("Refresh display", 2000)
-            self.app_window.device_connection.refresh_display(5000)
+            self.app_window.device_call(
+                "Refreshing display",
+                self.app_window.device_connection.refresh_display,
+                5000,
+            )
 
 
 class StatusPage(QWidget):
@@ -248,18 +301,36 @@ class StatusPage(QWidget):
     @Slot()
     def StatusRefresh(self):
         if self.app_window.device_connection.is_connected():
-            device_status = self.app_window.device_connection.retreive_device_status(
-                5000
+            device_status = self.app_window.device_call(
+                "Refreshing device status",
+                self.app_window.device_connection.retreive_device_status,
+                5000,
             )
+            if device_status == None:
+                return
 
             status_text = f"""
 <h3>Device Status</h3>
 <b>Current EPD Page:</b> {device_status.current_epd_page}<br>
+"""
+            # Older firmware only reports the current page
+            if device_status.protocol_version > 0:
+                major, minor, patch = device_status.firmware_version
+                status_text += f"""
+<b>Firmware:</b> {major}.{minor}.{patch} (protocol version {device_status.protocol_version})<br>
+<b>Display:</b> {device_status.display_width}x{device_status.display_height}<br>
+<b>Storage:</b> {device_status.storage_free_kib} KiB free of {device_status.storage_total_kib} KiB<br>
+<b>Displayed App:</b> {device_status.displayed_app or "-"}<br>
+<b>Uptime:</b> {device_status.uptime_s} s<br>
 """
 
-            app_images_list = (
-                self.app_window.device_connection.retreive_app_images_list(5000)
+            app_images_list = self.app_window.device_call(
+                "Retreiving app images",
+                self.app_window.device_connection.retreive_app_images_list,
+                5000,
             )
+            if app_images_list == None:
+                return
 
             status_text += "<b>App Images:</b><br>"
 
@@ -290,6 +361,35 @@ class EditPage(QWidget):
             self.__onImportFileDialogButtonClicked
         )
 
+        self.dithering_combo_box = QComboBox()
+        self.dithering_combo_box.addItem("Threshold", Dithering.Threshold)
+        self.dithering_combo_box.addItem("Floyd-Steinberg", Dithering.FloydSteinberg)
+        self.dithering_combo_box.addItem("Atkinson", Dithering.Atkinson)
+        self.dithering_combo_box.addItem("Bayer 4x4", Dithering.Bayer4x4)
+        self.dithering_combo_box.addItem("Bayer 8x8", Dithering.Bayer8x8)
+
+        self.fit_combo_box = QComboBox()
+        self.fit_combo_box.addItem("Stretch", FitMode.Stretch)
+        self.fit_combo_box.addItem("Contain", FitMode.Contain)
+        self.fit_combo_box.addItem("Cover", FitMode.Cover)
+        self.fit_combo_box.addItem("Center", FitMode.Center)
+
+        self.grayscale_check_box = QCheckBox("4 gray levels")
+
+        self.export_options_container = QWidget()
+        self.export_options_container.layout = QHBoxLayout(
+            self.export_options_container
+        )
+        self.export_options_container.layout.addWidget(QLabel("Dithering:"))
+        self.export_options_container.layout.addWidget(self.dithering_combo_box)
+        self.export_options_container.layout.addWidget(QLabel("Fit:"))
+        self.export_options_container.layout.addWidget(self.fit_combo_box)
+        self.export_options_container.layout.addWidget(self.grayscale_check_box)
+
+        self.preview_button = QPushButton("Preview")
+        self.preview_button.clicked.connect(self.PreviewImageFile)
+        self.export_options_container.layout.addWidget(self.preview_button)
+
         self.send_user_image_button = QPushButton("Send as User Image")
         self.send_user_image_button.clicked.connect(self.SendUserImageFile)
 
@@ -316,10 +416,22 @@ class EditPage(QWidget):
         self.layout.addWidget(
             self.graphics_view, alignment=(Qt.AlignCenter | Qt.AlignTop)
         )
+        self.layout.addWidget(
+            self.export_options_container, alignment=(Qt.AlignLeft | Qt.AlignTop)
+        )
         self.layout.addWidget(self.edit_controls_container)
         self.layout.setStretch(0, 0)
         self.layout.setStretch(1, 1)
         self.layout.setStretch(2, 0)
+        self.layout.setStretch(3, 0)
+
+    def export_options(self) -> ExportOptions:
+        options = ExportOptions.new()
+        options.dithering = self.dithering_combo_box.currentData()
+        options.fit = self.fit_combo_box.currentData()
+        if self.grayscale_check_box.isChecked():
+            options.bit_depth = BitDepth.Bpp2
+        return options
 
     def __updateScenePixmap(self):
         new_pixmap = QPixmap(self.image_file)
@@ -337,18 +449,48 @@ class EditPage(QWidget):
         )
         self.__updateScenePixmap()
 
+    @Slot()
+    def PreviewImageFile(self):
+        if self.image_file != None:
+            try:
+                # Render for the display of the connected device, if there is one
+                capabilities = None
+                if self.app_window.device_connection.is_connected():
+                    capabilities = self.app_window.device_connection.capabilities()
+
+                preview_png = preview_image_from_file(
+                    self.image_file, self.export_options(), capabilities
+                )
+            except ImageError as e:
+                QMessageBox.warning(self, app_name, f"Rendering preview failed: {e}")
+                return
+
+            preview_pixmap = QPixmap()
+            preview_pixmap.loadFromData(preview_png)
+            self.pixmapitem.setPixmap(preview_pixmap)
+            self.scene.update()
+
     @Slot()
     def SendUserImageFile(self):
         if self.image_file != None:
             if self.app_window.device_connection.is_connected():
-                self.app_window.device_connection.convert_send_user_image_from_file(
-                    self.image_file, 5000
+                self.app_window.device_call(
+                    "Sending user image",
+                    self.app_window.device_connection.convert_send_user_image_from_file,
+                    self.image_file,
+                    5000,
+                    self.export_options(),
                 )
 
     @Slot()
     def SendAppImageFile(self):
         if self.image_file != None:
             if self.app_window.device_connection.is_connected():
-                self.app_window.device_connection.convert_send_app_image_from_file(
-                    self.app_image_name_edit.text(), self.image_file, 5000
+                self.app_window.device_call(
+                    "Sending app image",
+                    self.app_window.device_connection.convert_send_app_image_from_file,
+                    self.app_image_name_edit.text(),
+                    self.image_file,
+                    5000,
+                    self.export_options(),
                 )